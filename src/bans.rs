@@ -0,0 +1,303 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    net::IpAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+///Who a ban keeps out
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BanTarget {
+    ///The GUID the game sends when joining, only known once the client joins
+    Guid(String),
+    Ip(IpAddr),
+    ///A whole range of adresses, like 10.0.0.0/8
+    Cidr(String),
+}
+
+impl BanTarget {
+    ///Read a target typed by the operator: a CIDR range, an IP or a GUID
+    pub fn parse(text: &str) -> Result<BanTarget, String> {
+        if text.contains('/') {
+            return match cidr(text) {
+                Some(_) => Ok(BanTarget::Cidr(text.to_owned())),
+                None => Err(format!("{text} is not a valid CIDR range")),
+            };
+        }
+        if let Ok(ip) = text.parse() {
+            return Ok(BanTarget::Ip(ip));
+        }
+        if text.len() == 36 && text.chars().all(|e| e.is_ascii_hexdigit() || e == '-') {
+            return Ok(BanTarget::Guid(text.to_lowercase()));
+        }
+        Err(format!("{text} is not a GUID, IP or CIDR range"))
+    }
+
+    fn matches_ip(&self, ip: IpAddr) -> bool {
+        match self {
+            BanTarget::Guid(_) => false,
+            BanTarget::Ip(banned) => *banned == ip,
+            BanTarget::Cidr(range) => {
+                cidr(range).is_some_and(|(network, bits)| match (network, ip) {
+                    (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                        let mask = u32::MAX.checked_shl(32 - bits).unwrap_or(0);
+                        u32::from(network) & mask == u32::from(ip) & mask
+                    }
+                    (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                        let mask = u128::MAX.checked_shl(128 - bits).unwrap_or(0);
+                        u128::from(network) & mask == u128::from(ip) & mask
+                    }
+                    _ => false,
+                })
+            }
+        }
+    }
+
+    fn matches_guid(&self, guid: &str) -> bool {
+        match self {
+            BanTarget::Guid(banned) => banned.eq_ignore_ascii_case(guid),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for BanTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BanTarget::Guid(guid) => write!(f, "GUID {guid}"),
+            BanTarget::Ip(ip) => write!(f, "IP {ip}"),
+            BanTarget::Cidr(range) => write!(f, "range {range}"),
+        }
+    }
+}
+
+///Split a CIDR range into its network and how many bits of it count
+fn cidr(range: &str) -> Option<(IpAddr, u32)> {
+    let (network, bits) = range.split_once('/')?;
+    let network: IpAddr = network.parse().ok()?;
+    let bits: u32 = bits.parse().ok()?;
+
+    let max = match network {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+    (bits <= max).then_some((network, bits))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.as_secs())
+        .unwrap_or(0)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Ban {
+    pub target: BanTarget,
+    pub reason: String,
+    ///Unix time in seconds when the ban is over, None if it never is
+    #[serde(default)]
+    pub expires: Option<u64>,
+}
+
+impl Ban {
+    pub fn is_expired(&self) -> bool {
+        self.expires.is_some_and(|e| e <= now())
+    }
+
+    ///What the banned client is told when it gets disconnected
+    pub fn message(&self) -> String {
+        match self.expires {
+            Some(expires) => format!(
+                "You are banned from this server for {} more minute(s): {}",
+                expires.saturating_sub(now()).div_ceil(60),
+                self.reason
+            ),
+            None => format!("You are banned from this server: {}", self.reason),
+        }
+    }
+}
+
+impl std::fmt::Display for Ban {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let target = &self.target;
+        match self.expires {
+            Some(expires) => write!(
+                f,
+                "{target} ({}) for {} more minute(s)",
+                self.reason,
+                expires.saturating_sub(now()).div_ceil(60)
+            ),
+            None => write!(f, "{target} ({}) permanently", self.reason),
+        }
+    }
+}
+
+///Everyone who isn't allowed on the server, saved in bans.json
+#[derive(Deserialize, Serialize, Debug, Default)]
+#[serde(transparent)]
+pub struct BanList {
+    bans: Vec<Ban>,
+}
+
+impl BanList {
+    ///Ban someone, for `duration` or forever if it's None
+    pub fn add(
+        &mut self,
+        target: BanTarget,
+        reason: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), String> {
+        let expires = match duration {
+            Some(duration) => Some(
+                now()
+                    .checked_add(duration.as_secs())
+                    .ok_or("The ban is too long")?,
+            ),
+            None => None,
+        };
+
+        self.bans.retain(|e| e.target != target);
+        self.bans.push(Ban {
+            target,
+            reason: reason.to_owned(),
+            expires,
+        });
+        Ok(())
+    }
+
+    ///Lift every ban on `target`, returns whether there was one
+    pub fn remove(&mut self, target: &BanTarget) -> bool {
+        let before = self.bans.len();
+        self.bans.retain(|e| e.target != *target);
+        self.bans.len() != before
+    }
+
+    ///Forget the bans that are over
+    pub fn prune(&mut self) {
+        self.bans.retain(|e| !e.is_expired());
+    }
+
+    pub fn bans(&self) -> &[Ban] {
+        &self.bans
+    }
+
+    pub fn find_ip(&self, ip: IpAddr) -> Option<&Ban> {
+        self.bans
+            .iter()
+            .find(|e| !e.is_expired() && e.target.matches_ip(ip))
+    }
+
+    pub fn find_guid(&self, guid: &str) -> Option<&Ban> {
+        self.bans
+            .iter()
+            .find(|e| !e.is_expired() && e.target.matches_guid(guid))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn range(text: &str) -> BanTarget {
+        BanTarget::parse(text).unwrap()
+    }
+
+    fn ban(expires: Option<u64>) -> Ban {
+        Ban {
+            target: BanTarget::Ip(ip("10.0.0.1")),
+            reason: "Testing".to_owned(),
+            expires,
+        }
+    }
+
+    #[test]
+    fn cidr_zero_matches_everything() {
+        assert!(range("0.0.0.0/0").matches_ip(ip("203.0.113.9")));
+        assert!(range("0.0.0.0/0").matches_ip(ip("255.255.255.255")));
+        assert!(range("::/0").matches_ip(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn full_length_cidr_matches_one_adress() {
+        assert!(range("192.168.1.10/32").matches_ip(ip("192.168.1.10")));
+        assert!(!range("192.168.1.10/32").matches_ip(ip("192.168.1.11")));
+        assert!(range("2001:db8::1/128").matches_ip(ip("2001:db8::1")));
+        assert!(!range("2001:db8::1/128").matches_ip(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn cidr_matches_its_range() {
+        assert!(range("10.0.0.0/8").matches_ip(ip("10.255.0.1")));
+        assert!(!range("10.0.0.0/8").matches_ip(ip("11.0.0.1")));
+        assert!(range("2001:db8::/32").matches_ip(ip("2001:db8:ffff::1")));
+        assert!(!range("2001:db8::/32").matches_ip(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn ipv4_never_matches_ipv6() {
+        assert!(!range("0.0.0.0/0").matches_ip(ip("::1")));
+        assert!(!range("::/0").matches_ip(ip("127.0.0.1")));
+        assert!(!range("127.0.0.1").matches_ip(ip("::ffff:127.0.0.1")));
+    }
+
+    #[test]
+    fn invalid_cidr_is_rejected() {
+        assert!(BanTarget::parse("10.0.0.0/33").is_err());
+        assert!(BanTarget::parse("::/129").is_err());
+        assert!(BanTarget::parse("10.0.0.0/x").is_err());
+    }
+
+    #[test]
+    fn bans_expire() {
+        assert!(!ban(None).is_expired());
+        assert!(!ban(Some(now() + 60)).is_expired());
+        assert!(ban(Some(now())).is_expired());
+        assert!(ban(Some(now() - 60)).is_expired());
+    }
+
+    #[test]
+    fn expired_bans_are_ignored_and_pruned() {
+        let mut list = BanList::default();
+        list.bans.push(ban(Some(now() - 60)));
+        assert!(list.find_ip(ip("10.0.0.1")).is_none());
+
+        list.prune();
+        assert!(list.bans().is_empty());
+
+        list.add(
+            BanTarget::Ip(ip("10.0.0.1")),
+            "Testing",
+            Some(Duration::from_secs(60)),
+        )
+        .unwrap();
+        assert!(list.find_ip(ip("10.0.0.1")).is_some());
+        list.prune();
+        assert_eq!(list.bans().len(), 1);
+    }
+
+    #[test]
+    fn ban_too_long_to_expire_is_refused() {
+        let mut list = BanList::default();
+        let target = BanTarget::Ip(ip("10.0.0.1"));
+
+        let forever = Some(Duration::from_secs(u64::MAX));
+        assert!(list.add(target.clone(), "Testing", forever).is_err());
+        assert!(list.bans().is_empty());
+
+        list.add(target.clone(), "Testing", None).unwrap();
+        assert!(list.find_ip(ip("10.0.0.1")).is_some());
+    }
+
+    #[test]
+    fn targets_are_shown_readably() {
+        assert_eq!(range("10.0.0.0/8").to_string(), "range 10.0.0.0/8");
+        assert_eq!(range("::1").to_string(), "IP ::1");
+        let guid = "0123456789ab-cdef-0123-4567-89abcdef";
+        assert_eq!(range(guid).to_string(), format!("GUID {guid}"));
+    }
+}
@@ -5,28 +5,51 @@ use crate::{
     to_byte,
 };
 
+///Every message starts with its header, sequence and size
+pub const HEADER_SIZE: usize = 5;
+
+///Lidgren's default MTU, anything bigger has to be fragmented
+pub const DEFAULT_MTU: usize = 1408;
+
+///Room for the biggest fragment header we could write (four 5 byte varints)
+const FRAGMENT_HEADER_SIZE: usize = 20;
+
 pub struct Buffer {
-    pub payload: [u8; 1500],
+    pub payload: Vec<u8>,
     pub seq: usize,
-    ptr: usize,
+    ///How many bits of the last byte are used, 0 if it's full. Lidgren writes bools as one bit.
+    bits: u32,
 }
 
 impl Buffer {
-    ///Write a single byte into the buffer and shift the pointer to the right.
+    ///Write a single byte at the end of the buffer, it grows as needed.
+    ///After a bool the byte is split over the free bits of the last byte and a new one, like Lidgren does.
     pub fn write_byte(&mut self, byte: u8) {
-        self.payload[self.ptr] = byte;
-        self.ptr += 1;
+        match self.bits {
+            0 => self.payload.push(byte),
+            bits => {
+                if let Some(last) = self.payload.last_mut() {
+                    *last |= byte << bits;
+                }
+                self.payload.push(byte >> (8 - bits));
+            }
+        }
+    }
+
+    ///Write a number 7 bits at a time, the high bit says if there's more to come
+    pub fn write_varint(&mut self, num: usize) {
+        let mut num = num;
+        while num >= 0x80 {
+            self.write_byte(to_byte(num | 0x80));
+            num >>= 7;
+        }
+        self.write_byte(to_byte(num));
     }
 
     pub fn write_string(&mut self, string: &str) {
         let bytes = string.as_bytes();
 
-        let mut size = bytes.len();
-        while size >= 0x80 {
-            self.write_byte(to_byte(size | 0x80));
-            size >>= 7;
-        }
-        self.write_byte(to_byte(size));
+        self.write_varint(bytes.len());
 
         for byte in bytes.iter() {
             self.write_byte(*byte);
@@ -45,8 +68,15 @@ impl Buffer {
         }
     }
 
+    ///Write a bool as a single bit, everything after it is no longer byte aligned
     pub fn write_bool(&mut self, statement: bool) {
-        self.write_byte(statement.into());
+        if self.bits == 0 {
+            self.payload.push(0);
+        }
+        if let Some(last) = self.payload.last_mut() {
+            *last |= u8::from(statement) << self.bits;
+        }
+        self.bits = (self.bits + 1) % 8;
     }
 
     pub fn write_guid(&mut self, guid: Vec<u8>) {
@@ -83,7 +113,7 @@ impl Buffer {
         self.write_f32(&vector[3]);
     }
 
-    pub fn write_clients(&mut self, clients: &Vec<Client>) {
+    pub fn write_clients(&mut self, clients: &[Client]) {
         self.write_i32(&clients.len().try_into().unwrap());
         for client in clients.iter() {
             self.write_guid(client.guid.clone());
@@ -91,7 +121,7 @@ impl Buffer {
         }
     }
 
-    pub fn write_players(&mut self, players: &Vec<Player>) {
+    pub fn write_players(&mut self, players: &[Player]) {
         self.write_i32(&players.len().try_into().unwrap());
         for player in players.iter() {
             self.write_guid(player.guid.clone());
@@ -111,7 +141,7 @@ impl Buffer {
         self.write_i32(&setting.auto_start_min_players); //Int32
         self.write_i32(&setting.auto_return_time); //Int32
         self.write_f32(&setting.vote_ratio); //Float
-        self.write_i32(&setting.stage_rotation_mode); //Int32 (Cast to StageRotationMode)
+        self.write_i32(&setting.stage_rotation_mode.into()); //Int32 (Cast to StageRotationMode)
     }
 
     pub fn write_player_position(&mut self, player: &PlayerPosition) {
@@ -133,15 +163,22 @@ impl Buffer {
 
     /// Write the header, sequence and full size into the buffer. You cannot write more after this.
     pub fn write_header(&mut self, header: Header) {
+        self.write_header_byte(header as u8);
+    }
+
+    fn write_header_byte(&mut self, header: u8) {
         // Special number that tells Unity how to respond
-        self.payload[0] = header as u8;
+        self.payload[0] = header;
 
         // Sequence, used for multi-part convos and split in two for int support
-        self.payload[1] = to_byte(self.seq << 1);
-        self.payload[2] = to_byte(self.seq >> 7);
+        self.seq(self.seq);
 
-        // Remove the assumed 5 bytes then convert to bits
-        let bits = (self.ptr - 5) * 8;
+        // Remove the assumed 5 bytes then convert to bits, minus what's unused of the last byte
+        let unused = match self.bits {
+            0 => 0,
+            bits => 8 - bits as usize,
+        };
+        let bits = (self.payload.len() - HEADER_SIZE) * 8 - unused;
 
         // Message size in bits, split in two for int support
         self.payload[3] = to_byte(bits); // Low bits
@@ -149,26 +186,64 @@ impl Buffer {
     }
 
     pub fn seq(&mut self, counter: usize) {
-        self.payload[1] = to_byte(counter << 1);
-        self.payload[2] = to_byte(counter >> 7);
+        set_sequence(&mut self.payload, counter);
     }
 
-    /// Return the slice into the message we should send (a 1500 length array isn't good to send)
+    /// Return the bytes of the message we should send
     pub fn message(&self) -> Vec<u8> {
-        self.payload[..self.ptr].to_owned()
+        self.payload.clone()
+    }
+
+    /// Whether the message is too big to be sent as is
+    pub fn is_oversize(&self, mtu: usize) -> bool {
+        self.payload.len() > mtu || self.payload.len() - HEADER_SIZE > usize::from(u16::MAX) / 8
+    }
+
+    /// Split a message with its header written into Lidgren fragments that each fit in the MTU.
+    /// Every fragment still needs its own sequence number.
+    pub fn fragments(&self, group: u32, mtu: usize) -> Vec<Vec<u8>> {
+        let data = &self.payload[HEADER_SIZE..];
+        let chunk_size = mtu - HEADER_SIZE - FRAGMENT_HEADER_SIZE;
+
+        data.chunks(chunk_size)
+            .enumerate()
+            .map(|(number, chunk)| {
+                let mut fragment = Buffer::default();
+
+                // Fragment header, tells the other side where this chunk goes
+                fragment.write_varint(group.try_into().unwrap());
+                fragment.write_varint(data.len() * 8);
+                fragment.write_varint(chunk_size);
+                fragment.write_varint(number);
+
+                for byte in chunk {
+                    fragment.write_byte(*byte);
+                }
+
+                fragment.write_header_byte(self.payload[0]);
+                fragment.payload[1] |= 1;
+                fragment.message()
+            })
+            .collect()
     }
 }
 
 impl Default for Buffer {
     fn default() -> Self {
         Buffer {
-            payload: [0; 1500],
-            ptr: 5,
+            payload: vec![0; HEADER_SIZE],
             seq: 0,
+            bits: 0,
         }
     }
 }
 
+/// Put a sequence number into a written message, keeping the flag that marks fragments
+pub fn set_sequence(message: &mut [u8], sequence: usize) {
+    message[1] = to_byte(sequence << 1) | (message[1] & 1);
+    message[2] = to_byte(sequence >> 7);
+}
+
 ///Turn a Rust JSON into a C# JSON
 pub fn sharpize(json: String, substring: &'static str) -> String {
     json.replace(
@@ -176,3 +251,103 @@ pub fn sharpize(json: String, substring: &'static str) -> String {
         &format!("SanicballCore.MatchMessages.{substring}, SanicballCore"),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::Stream;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    fn origin() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 25000)
+    }
+
+    fn message(size: usize) -> Buffer {
+        let mut buffer = Buffer::default();
+        for byte in 0..size {
+            buffer.write_byte(to_byte(byte % 251));
+        }
+        buffer.write_header(Header::UserReliableOrdered1);
+        buffer
+    }
+
+    #[test]
+    fn fragments_fit_the_mtu_and_cover_the_message() {
+        let buffer = message(3000);
+        let fragments = buffer.fragments(7, DEFAULT_MTU);
+        assert_eq!(fragments.len(), 3);
+
+        let mut data = vec![];
+        for (number, fragment) in fragments.iter().enumerate() {
+            assert!(fragment.len() <= DEFAULT_MTU);
+
+            let mut stream = Stream::new(fragment, origin()).unwrap();
+            assert!(stream.fragment);
+            assert_eq!(stream.header_byte, Header::UserReliableOrdered1 as u8);
+            assert_eq!(stream.read_varint().unwrap(), 7);
+            assert_eq!(stream.read_varint().unwrap(), 3000 * 8);
+            assert_eq!(
+                stream.read_varint().unwrap(),
+                DEFAULT_MTU - HEADER_SIZE - FRAGMENT_HEADER_SIZE
+            );
+            assert_eq!(stream.read_varint().unwrap(), number);
+            data.extend_from_slice(stream.rest());
+        }
+
+        assert_eq!(data, buffer.payload[HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn fragment_flag_survives_the_sequence_number() {
+        let buffer = message(2000);
+        let mut fragment = buffer.fragments(1, DEFAULT_MTU).remove(0);
+        set_sequence(&mut fragment, 1000);
+
+        let stream = Stream::new(&fragment, origin()).unwrap();
+        assert!(stream.fragment);
+        assert_eq!(stream.sequence, 1000);
+    }
+
+    #[test]
+    fn message_under_the_mtu_is_one_fragment() {
+        let buffer = message(100);
+        let fragments = buffer.fragments(1, DEFAULT_MTU);
+        assert_eq!(fragments.len(), 1);
+
+        let mut stream = Stream::new(&fragments[0], origin()).unwrap();
+        for _ in 0..4 {
+            stream.read_varint().unwrap();
+        }
+        assert_eq!(stream.rest(), &buffer.payload[HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn bools_are_one_bit() {
+        let mut buffer = Buffer::default();
+        buffer.write_bool(true);
+        buffer.write_bool(false);
+        buffer.write_bool(true);
+        buffer.write_header(Header::UserUnreliable);
+
+        assert_eq!(buffer.payload[HEADER_SIZE..], [0b101]);
+        //The size is in bits, the rest of the byte is padding
+        assert_eq!(buffer.payload[3..5], [3, 0]);
+    }
+
+    #[test]
+    fn bytes_after_a_bool_are_not_aligned() {
+        let mut buffer = Buffer::default();
+        buffer.write_bool(true);
+        buffer.write_f32(&1.5);
+        buffer.write_bool(true);
+        buffer.write_byte(0xff);
+        buffer.write_header(Header::UserUnreliable);
+
+        //1.5 is 00 00 c0 3f, shifted up by the bool
+        assert_eq!(
+            buffer.payload[HEADER_SIZE..],
+            [0x01, 0x00, 0x80, 0x7f, 0xfe, 0x03]
+        );
+        assert_eq!(buffer.payload[3..5], [42, 0]);
+    }
+}
@@ -0,0 +1,407 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::{buffer::set_sequence, headers::Delivery, stream::Stream};
+
+///Lidgren wraps its sequence numbers around after this many messages
+pub const SEQUENCE_NUMBERS: u16 = 1024;
+
+///How many reliable messages can be unacknowledged at once on a channel
+pub const WINDOW_SIZE: u16 = 64;
+
+///How far ahead (or behind, if negative) `sequence` is from `expected`, taking the wrap-around into account
+pub fn relative_sequence(sequence: u16, expected: u16) -> i32 {
+    let numbers = i32::from(SEQUENCE_NUMBERS);
+    (i32::from(sequence) - i32::from(expected) + numbers + numbers / 2) % numbers - numbers / 2
+}
+
+fn next(sequence: u16) -> u16 {
+    (sequence + 1) % SEQUENCE_NUMBERS
+}
+
+///A reliable message that was sent but hasn't been acknowledged yet
+struct StoredMessage {
+    sequence: u16,
+    message: Vec<u8>,
+    last_sent: Instant,
+}
+
+///Our side of one channel, every channel counts its sequence numbers on its own
+#[derive(Default)]
+pub struct SendChannel {
+    next_sequence: u16,
+    unacked: Vec<StoredMessage>,
+    queued: VecDeque<Vec<u8>>,
+}
+
+impl SendChannel {
+    ///Give an unreliable message the next sequence number, so the other side can drop it if it comes in late
+    pub fn sequence(&mut self, mut message: Vec<u8>) -> Vec<u8> {
+        set_sequence(&mut message, self.next_sequence.into());
+        self.next_sequence = next(self.next_sequence);
+        message
+    }
+
+    ///Give a reliable message its sequence number and remember it until it's acknowledged.
+    ///Returns None if the message has to wait until the window has room.
+    pub fn send_reliable(&mut self, message: Vec<u8>) -> Option<Vec<u8>> {
+        //Anything already waiting goes first, otherwise the order would get mixed up
+        if !self.queued.is_empty() || self.unacked.len() >= usize::from(WINDOW_SIZE) {
+            self.queued.push_back(message);
+            return None;
+        }
+
+        Some(self.store(message))
+    }
+
+    fn store(&mut self, message: Vec<u8>) -> Vec<u8> {
+        let sequence = self.next_sequence;
+        let message = self.sequence(message);
+        self.unacked.push(StoredMessage {
+            sequence,
+            message: message.clone(),
+            last_sent: Instant::now(),
+        });
+        message
+    }
+
+    ///The other side got one of our messages, it doesn't need to be resent anymore
+    pub fn acknowledge(&mut self, sequence: u16) {
+        //Not there if it was already acked, the ack was probably resent
+        self.unacked.retain(|e| e.sequence != sequence);
+    }
+
+    ///Messages that waited longer than `delay` for an ack, plus any that now fit in the window
+    pub fn resend(&mut self, delay: Duration) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        let mut messages = vec![];
+
+        for stored in self.unacked.iter_mut() {
+            if now.duration_since(stored.last_sent) > delay {
+                stored.last_sent = now;
+                messages.push(stored.message.clone());
+            }
+        }
+
+        while self.unacked.len() < usize::from(WINDOW_SIZE) {
+            let Some(message) = self.queued.pop_front() else {
+                break;
+            };
+            messages.push(self.store(message));
+        }
+
+        messages
+    }
+}
+
+///The other side of a channel, decides which incoming messages get handled and when
+#[derive(Default)]
+pub struct ReceiveChannel {
+    expected: u16,
+    //Ordered messages that arrived before the ones in front of them
+    withheld: HashMap<u16, Stream>,
+    //Unordered messages that were already handled ahead of `expected`
+    early: HashSet<u16>,
+}
+
+impl ReceiveChannel {
+    ///Returns every message that can be handled now, oldest first
+    pub fn receive(&mut self, delivery: Delivery, stream: Stream) -> Vec<Stream> {
+        let relate = relative_sequence(stream.sequence, self.expected);
+
+        match delivery {
+            Delivery::Unreliable => vec![stream],
+            //Only the newest message matters, anything older that shows up late is dropped
+            Delivery::Sequenced | Delivery::ReliableSequenced => {
+                if relate < 0 {
+                    return vec![];
+                }
+                self.expected = next(stream.sequence);
+                vec![stream]
+            }
+            Delivery::ReliableUnordered => {
+                //Duplicate of something we already handled, or too far ahead to keep track of
+                if relate < 0 || relate >= i32::from(WINDOW_SIZE) {
+                    return vec![];
+                }
+                if relate > 0 {
+                    return match self.early.insert(stream.sequence) {
+                        true => vec![stream],
+                        false => vec![],
+                    };
+                }
+
+                self.expected = next(self.expected);
+                while self.early.remove(&self.expected) {
+                    self.expected = next(self.expected);
+                }
+                vec![stream]
+            }
+            Delivery::ReliableOrdered => {
+                if relate < 0 || relate >= i32::from(WINDOW_SIZE) {
+                    return vec![];
+                }
+                if relate > 0 {
+                    self.withheld.insert(stream.sequence, stream);
+                    return vec![];
+                }
+
+                let mut ready = vec![stream];
+                self.expected = next(self.expected);
+
+                //Messages that arrived early are handled once the gap before them is filled
+                while let Some(next_stream) = self.withheld.remove(&self.expected) {
+                    ready.push(next_stream);
+                    self.expected = next(self.expected);
+                }
+                ready
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::to_byte;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    ///A message on the first ReliableOrdered channel carrying its own sequence number
+    fn stream(sequence: u16) -> Stream {
+        let datagram = [
+            67,
+            to_byte(usize::from(sequence << 1)),
+            to_byte(usize::from(sequence >> 7)),
+            16,
+            0,
+            to_byte(usize::from(sequence)),
+            to_byte(usize::from(sequence >> 8)),
+        ];
+        let origin = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 25000);
+        Stream::new(&datagram, origin).unwrap()
+    }
+
+    fn sequences(streams: &[Stream]) -> Vec<u16> {
+        streams.iter().map(|e| e.sequence).collect()
+    }
+
+    #[test]
+    fn relative_sequence_wraps_around() {
+        assert_eq!(relative_sequence(5, 5), 0);
+        assert_eq!(relative_sequence(6, 5), 1);
+        assert_eq!(relative_sequence(4, 5), -1);
+        assert_eq!(relative_sequence(0, 1023), 1);
+        assert_eq!(relative_sequence(1023, 0), -1);
+        assert_eq!(relative_sequence(10, 1020), 14);
+        assert_eq!(relative_sequence(1020, 10), -14);
+        assert_eq!(relative_sequence(511, 0), 511);
+        assert_eq!(relative_sequence(512, 0), -512);
+    }
+
+    #[test]
+    fn ordered_messages_wait_for_the_gap() {
+        let mut channel = ReceiveChannel::default();
+
+        assert!(channel
+            .receive(Delivery::ReliableOrdered, stream(2))
+            .is_empty());
+        assert!(channel
+            .receive(Delivery::ReliableOrdered, stream(1))
+            .is_empty());
+
+        let ready = channel.receive(Delivery::ReliableOrdered, stream(0));
+        assert_eq!(sequences(&ready), [0, 1, 2]);
+
+        let ready = channel.receive(Delivery::ReliableOrdered, stream(3));
+        assert_eq!(sequences(&ready), [3]);
+    }
+
+    #[test]
+    fn ordered_messages_continue_past_the_wrap_around() {
+        let mut channel = ReceiveChannel {
+            expected: SEQUENCE_NUMBERS - 1,
+            ..Default::default()
+        };
+
+        assert!(channel
+            .receive(Delivery::ReliableOrdered, stream(0))
+            .is_empty());
+        let ready = channel.receive(Delivery::ReliableOrdered, stream(SEQUENCE_NUMBERS - 1));
+        assert_eq!(sequences(&ready), [SEQUENCE_NUMBERS - 1, 0]);
+        assert_eq!(channel.expected, 1);
+    }
+
+    #[test]
+    fn ordered_duplicates_and_far_messages_are_dropped() {
+        let mut channel = ReceiveChannel::default();
+        assert_eq!(
+            channel.receive(Delivery::ReliableOrdered, stream(0)).len(),
+            1
+        );
+
+        //Already handled
+        assert!(channel
+            .receive(Delivery::ReliableOrdered, stream(0))
+            .is_empty());
+        //Outside the window
+        assert!(channel
+            .receive(Delivery::ReliableOrdered, stream(1 + WINDOW_SIZE))
+            .is_empty());
+        assert!(channel.withheld.is_empty());
+
+        //A withheld message that's resent is only handled once
+        assert!(channel
+            .receive(Delivery::ReliableOrdered, stream(2))
+            .is_empty());
+        assert!(channel
+            .receive(Delivery::ReliableOrdered, stream(2))
+            .is_empty());
+        let ready = channel.receive(Delivery::ReliableOrdered, stream(1));
+        assert_eq!(sequences(&ready), [1, 2]);
+    }
+
+    #[test]
+    fn unordered_messages_are_handled_once() {
+        let mut channel = ReceiveChannel::default();
+
+        assert_eq!(
+            channel
+                .receive(Delivery::ReliableUnordered, stream(1))
+                .len(),
+            1
+        );
+        assert!(channel
+            .receive(Delivery::ReliableUnordered, stream(1))
+            .is_empty());
+        assert_eq!(
+            channel
+                .receive(Delivery::ReliableUnordered, stream(0))
+                .len(),
+            1
+        );
+        assert!(channel
+            .receive(Delivery::ReliableUnordered, stream(0))
+            .is_empty());
+        assert_eq!(channel.expected, 2);
+        assert!(channel
+            .receive(Delivery::ReliableUnordered, stream(2 + WINDOW_SIZE))
+            .is_empty());
+    }
+
+    #[test]
+    fn stale_sequenced_messages_are_dropped() {
+        let mut channel = ReceiveChannel::default();
+
+        assert_eq!(channel.receive(Delivery::Sequenced, stream(5)).len(), 1);
+        assert!(channel.receive(Delivery::Sequenced, stream(3)).is_empty());
+        assert!(channel.receive(Delivery::Sequenced, stream(5)).is_empty());
+        assert_eq!(channel.receive(Delivery::Sequenced, stream(6)).len(), 1);
+
+        //Still newer after the wrap-around
+        let mut channel = ReceiveChannel {
+            expected: SEQUENCE_NUMBERS - 2,
+            ..Default::default()
+        };
+        assert_eq!(
+            channel
+                .receive(Delivery::ReliableSequenced, stream(1))
+                .len(),
+            1
+        );
+        assert!(channel
+            .receive(Delivery::ReliableSequenced, stream(SEQUENCE_NUMBERS - 1))
+            .is_empty());
+    }
+
+    ///A message on the first ReliableOrdered channel, `id` tells them apart
+    fn message(id: u8) -> Vec<u8> {
+        vec![67, 0, 0, 8, 0, id]
+    }
+
+    fn sequence_of(message: &[u8]) -> u16 {
+        u16::from(message[1] >> 1) | u16::from(message[2]) << 7
+    }
+
+    #[test]
+    fn send_window_holds_back_messages() {
+        let mut channel = SendChannel::default();
+        for id in 0..WINDOW_SIZE {
+            let sent = channel.send_reliable(message(to_byte(id.into()))).unwrap();
+            assert_eq!(sequence_of(&sent), id);
+        }
+
+        assert!(channel.send_reliable(message(100)).is_none());
+        assert!(channel.send_reliable(message(101)).is_none());
+        assert_eq!(channel.queued.len(), 2);
+
+        //Nothing was acknowledged or timed out, so there's nothing to send yet
+        assert!(channel.resend(Duration::from_secs(60)).is_empty());
+    }
+
+    #[test]
+    fn acknowledge_slides_the_window() {
+        let mut channel = SendChannel::default();
+        for id in 0..WINDOW_SIZE {
+            channel.send_reliable(message(to_byte(id.into())));
+        }
+        channel.send_reliable(message(100));
+        channel.send_reliable(message(101));
+
+        channel.acknowledge(0);
+        //Acknowledged twice, the ack was resent
+        channel.acknowledge(0);
+
+        let sent = channel.resend(Duration::from_secs(60));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0][5], 100);
+        assert_eq!(sequence_of(&sent[0]), WINDOW_SIZE);
+        assert_eq!(channel.queued.len(), 1);
+
+        //Queued messages stay in order even if the window has room
+        channel.acknowledge(1);
+        assert!(channel.send_reliable(message(102)).is_none());
+        let sent = channel.resend(Duration::from_secs(60));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0][5], 101);
+    }
+
+    #[test]
+    fn unacknowledged_messages_are_resent_after_the_delay() {
+        let mut channel = SendChannel::default();
+        channel.send_reliable(message(1));
+        channel.send_reliable(message(2));
+        channel.acknowledge(1);
+
+        assert!(channel.resend(Duration::from_millis(500)).is_empty());
+
+        channel.unacked[0].last_sent = Instant::now() - Duration::from_secs(1);
+        let sent = channel.resend(Duration::from_millis(500));
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0][5], 1);
+        assert_eq!(sequence_of(&sent[0]), 0);
+
+        //It waits for the whole delay again
+        assert!(channel.resend(Duration::from_millis(500)).is_empty());
+    }
+
+    #[test]
+    fn send_sequence_wraps_around() {
+        let mut channel = SendChannel {
+            next_sequence: SEQUENCE_NUMBERS - 1,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            sequence_of(&channel.sequence(message(1))),
+            SEQUENCE_NUMBERS - 1
+        );
+        let sent = channel.send_reliable(message(2)).unwrap();
+        assert_eq!(sequence_of(&sent), 0);
+        assert_eq!(sequence_of(&channel.sequence(message(3))), 1);
+
+        channel.acknowledge(0);
+        assert!(channel.unacked.is_empty());
+    }
+}
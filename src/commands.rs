@@ -1 +1,843 @@
-pub fn consume_command() {}
+use std::{
+    io::stdin,
+    net::SocketAddr,
+    str::FromStr,
+    sync::mpsc::{channel, Receiver},
+    thread,
+};
+
+use crate::{bans::BanTarget, game::StageRotationMode, server::Server};
+use std::time::Duration;
+
+///Who is allowed to run a command, higher levels can run everything lower levels can
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Permission {
+    ///Anyone in the match
+    Player,
+    ///The operator and anyone they trust with the server
+    Admin,
+}
+
+///Why a command didn't run
+#[derive(Debug)]
+pub enum CommandError {
+    ///The arguments don't fit the command, its usage is shown
+    Usage,
+    ///The command ran into something, the text says what
+    Failed(String),
+}
+
+impl From<String> for CommandError {
+    fn from(value: String) -> Self {
+        CommandError::Failed(value)
+    }
+}
+
+pub type CommandResult = Result<(), CommandError>;
+
+///Where a command writes its replies: the console, a chat whisper, a remote admin...
+pub trait Output {
+    fn line(&mut self, text: &str);
+}
+
+///Replies printed to the console
+pub struct ConsoleOutput;
+
+impl Output for ConsoleOutput {
+    fn line(&mut self, text: &str) {
+        println!("{text}");
+    }
+}
+
+///Replies kept to be sent somewhere later, like over chat once the command is done with the server
+impl Output for Vec<String> {
+    fn line(&mut self, text: &str) {
+        self.push(text.to_owned());
+    }
+}
+
+///The words that came after the command name
+pub struct Args {
+    words: Vec<String>,
+    caller: Option<SocketAddr>,
+}
+
+impl Args {
+    pub fn new(words: Vec<String>, caller: Option<SocketAddr>) -> Self {
+        Args { words, caller }
+    }
+
+    ///The client that ran the command, None for the console
+    pub fn caller(&self) -> Option<SocketAddr> {
+        self.caller
+    }
+
+    ///The client that ran the command, for commands that only make sense for one
+    pub fn client(&self) -> Result<SocketAddr, CommandError> {
+        self.caller.ok_or_else(|| {
+            "Only clients in the match can use this command"
+                .to_owned()
+                .into()
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    pub fn words(&self) -> &[String] {
+        &self.words
+    }
+
+    ///Read one argument, a missing or unreadable argument is a usage error
+    pub fn get<T: FromStr>(&self, index: usize) -> Result<T, CommandError> {
+        let word = self.words.get(index).ok_or(CommandError::Usage)?;
+        word.parse().map_err(|_| CommandError::Usage)
+    }
+
+    ///Read the argument of a command that takes exactly one
+    pub fn only<T: FromStr>(&self) -> Result<T, CommandError> {
+        if self.words.len() != 1 {
+            return Err(CommandError::Usage);
+        }
+        self.get(0)
+    }
+
+    ///Read a name that has to be put in quotes if it has spaces, like "Sanic Fan".
+    ///Returns the name and the index of the argument after it.
+    pub fn name(&self, index: usize) -> Result<(String, usize), CommandError> {
+        let first = self.words.get(index).ok_or(CommandError::Usage)?;
+        let Some(first) = first.strip_prefix('"') else {
+            return Ok((first.clone(), index + 1));
+        };
+
+        let mut words = vec![];
+        for (position, word) in self.words.iter().enumerate().skip(index) {
+            let word = if position == index { first } else { word };
+            if let Some(word) = word.strip_suffix('"') {
+                words.push(word);
+                let name = words.join(" ");
+                if name.is_empty() {
+                    return Err(CommandError::Usage);
+                }
+                return Ok((name, position + 1));
+            }
+            words.push(word);
+        }
+
+        //The quote was never closed
+        Err(CommandError::Usage)
+    }
+
+    ///Every argument put back together, for commands that take free text
+    pub fn text(&self) -> Result<String, CommandError> {
+        if self.words.is_empty() {
+            return Err(CommandError::Usage);
+        }
+        Ok(self.words.join(" "))
+    }
+
+    ///The arguments from `index` on put back together, empty if there aren't any
+    pub fn rest(&self, index: usize) -> String {
+        self.words.get(index..).unwrap_or_default().join(" ")
+    }
+}
+
+pub type Handler = Box<dyn Fn(&mut Server, &Args, &mut dyn Output) -> CommandResult>;
+
+///Something that can be typed in the console or anywhere else commands are read
+pub struct Command {
+    pub name: String,
+    pub aliases: Vec<String>,
+    ///How to call the command, shown when the arguments are wrong
+    pub usage: String,
+    pub help: String,
+    pub permission: Permission,
+    handler: Handler,
+}
+
+impl Command {
+    ///A new command only admins can run, with no aliases
+    pub fn new<F>(name: &str, usage: &str, help: &str, handler: F) -> Self
+    where
+        F: Fn(&mut Server, &Args, &mut dyn Output) -> CommandResult + 'static,
+    {
+        Command {
+            name: name.to_owned(),
+            aliases: vec![],
+            usage: usage.to_owned(),
+            help: help.to_owned(),
+            permission: Permission::Admin,
+            handler: Box::new(handler),
+        }
+    }
+
+    pub fn alias(mut self, alias: &str) -> Self {
+        self.aliases.push(alias.to_owned());
+        self
+    }
+
+    pub fn permission(mut self, permission: Permission) -> Self {
+        self.permission = permission;
+        self
+    }
+
+    fn is_called(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name)
+            || self.aliases.iter().any(|e| e.eq_ignore_ascii_case(name))
+    }
+}
+
+///Every command the server knows about.
+///The console, in-game chat or a remote admin all run their commands through one of these.
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: Vec<Command>,
+}
+
+impl CommandRegistry {
+    ///A registry without any commands except help
+    pub fn new() -> Self {
+        CommandRegistry { commands: vec![] }
+    }
+
+    ///The registry with every built-in command, named like in C#.
+    ///Embedders can register their own commands on top of these.
+    pub fn with_builtins() -> Self {
+        let mut registry = CommandRegistry::new();
+        register_builtins(&mut registry);
+        registry
+    }
+
+    ///Add a command, it replaces any command that already had its name
+    pub fn register(&mut self, command: Command) {
+        self.commands
+            .retain(|e| !e.name.eq_ignore_ascii_case(&command.name));
+        self.commands.push(command);
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        self.commands.iter().find(|e| e.is_called(name))
+    }
+
+    ///Run a line of input as whoever has `permission`, the replies go to `output`.
+    ///`caller` is the client that sent it, None if it didn't come from a client.
+    pub fn run(
+        &self,
+        server: &mut Server,
+        line: &str,
+        caller: Option<SocketAddr>,
+        permission: Permission,
+        output: &mut dyn Output,
+    ) {
+        let mut words = line.split_whitespace();
+        let Some(name) = words.next() else {
+            return;
+        };
+        let args = Args::new(words.map(|e| e.to_owned()).collect(), caller);
+
+        if name.eq_ignore_ascii_case("help") {
+            self.help(permission, output);
+            return;
+        }
+
+        let Some(command) = self.find(name) else {
+            output.line(&format!(
+                "Unknown command \"{name}\", type help to see every command"
+            ));
+            return;
+        };
+        if permission < command.permission {
+            output.line(&format!(
+                "You don't have permission to use {}",
+                command.name
+            ));
+            return;
+        }
+
+        match (command.handler)(server, &args, output) {
+            Ok(()) => {}
+            Err(CommandError::Usage) => output.line(&format!("Usage: {}", command.usage)),
+            Err(CommandError::Failed(reason)) => output.line(&reason),
+        }
+    }
+
+    ///Run the commands clients sent over chat, the replies are whispered back to them.
+    ///Admins can run everything, anyone else only what players are allowed to.
+    pub fn run_chat(&self, server: &mut Server) {
+        for (addr, line) in server.take_chat_commands() {
+            let permission = match server.is_admin(addr) {
+                true => Permission::Admin,
+                false => Permission::Player,
+            };
+
+            let mut replies = vec![];
+            self.run(server, &line, Some(addr), permission, &mut replies);
+            for reply in replies {
+                server.chat_to(&reply, addr);
+            }
+        }
+    }
+
+    ///List every command someone with `permission` can run
+    pub fn help(&self, permission: Permission, output: &mut dyn Output) {
+        output.line("Available commands:");
+        output.line("help - Show every command you can use");
+        for command in self.commands.iter().filter(|e| e.permission <= permission) {
+            match command.aliases.is_empty() {
+                true => output.line(&format!("{} - {}", command.usage, command.help)),
+                false => output.line(&format!(
+                    "{} - {} (also {})",
+                    command.usage,
+                    command.help,
+                    command.aliases.join(", ")
+                )),
+            }
+        }
+    }
+}
+
+///Reads the console on its own thread, so waiting for input doesn't hold up the server
+pub struct Console {
+    lines: Receiver<String>,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        let (sender, lines) = channel();
+
+        thread::spawn(move || {
+            for line in stdin().lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                //The server is gone
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Console { lines }
+    }
+
+    ///Every line typed since the last call
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.try_iter().collect()
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn register_builtins(registry: &mut CommandRegistry) {
+    registry.register(Command::new(
+        "say",
+        "say [message]",
+        "Send a chat message to everyone",
+        say,
+    ));
+    registry.register(Command::new(
+        "clients",
+        "clients",
+        "List the connected clients",
+        clients,
+    ));
+    registry.register(
+        Command::new(
+            "players",
+            "players",
+            "List the players in the match",
+            players,
+        )
+        .permission(Permission::Player),
+    );
+    registry.register(Command::new(
+        "kick",
+        "kick [client name/part of name, in quotes if it has spaces] [reason]",
+        "Kick a client off the server",
+        kick,
+    ));
+    registry.register(Command::new(
+        "ban",
+        "ban [client name/part of name, in quotes if it has spaces] [minutes, 0 for ever] [reason]",
+        "Kick a client and keep its GUID off the server",
+        ban,
+    ));
+    registry.register(Command::new(
+        "banIp",
+        "banIp [IP or CIDR range] [minutes, 0 for ever] [reason]",
+        "Keep an adress or a range of adresses off the server",
+        ban_ip,
+    ));
+    registry.register(Command::new(
+        "unban",
+        "unban [GUID, IP or CIDR range]",
+        "Lift a ban",
+        unban,
+    ));
+    registry.register(Command::new("bans", "bans", "List the bans", bans));
+    registry.register(Command::new(
+        "mute",
+        "mute [client name/part of name, quotes are optional]",
+        "Block a client's chat messages",
+        mute,
+    ));
+    registry.register(Command::new(
+        "unmute",
+        "unmute [client name/part of name, quotes are optional]",
+        "Let a muted client chat again",
+        unmute,
+    ));
+    registry.register(Command::new(
+        "returnToLobby",
+        "returnToLobby",
+        "End the race and go back to the lobby",
+        return_to_lobby,
+    ));
+    registry.register(Command::new(
+        "forceStart",
+        "forceStart",
+        "Start the race without waiting for everyone to be ready",
+        force_start,
+    ));
+    registry.register(
+        Command::new(
+            "showSettings",
+            "showSettings",
+            "Show the match settings",
+            show_settings,
+        )
+        .alias("settings")
+        .permission(Permission::Player),
+    );
+    registry.register(
+        Command::new("motd", "motd", "Show the message of the day", motd)
+            .permission(Permission::Player),
+    );
+    registry.register(
+        Command::new(
+            "voteLobby",
+            "voteLobby",
+            "Vote to end the race and go back to the lobby",
+            vote_lobby,
+        )
+        .permission(Permission::Player),
+    );
+    registry.register(
+        Command::new(
+            "login",
+            "login [password]",
+            "Become an admin with the admin password",
+            login,
+        )
+        .permission(Permission::Player),
+    );
+    registry.register(Command::new(
+        "reloadSettings",
+        "reloadSettings",
+        "Load the match settings from their file again",
+        reload_settings,
+    ));
+    registry.register(Command::new(
+        "reloadMOTD",
+        "reloadMOTD",
+        "Load the message of the day from its file again",
+        reload_motd,
+    ));
+    registry.register(Command::new(
+        "setStage",
+        "setStage [stage id]",
+        "Change the stage",
+        set_stage,
+    ));
+    registry.register(Command::new(
+        "setLaps",
+        "setLaps [laps, 1 or more]",
+        "Change how many laps a race has",
+        set_laps,
+    ));
+    registry.register(Command::new(
+        "setAutoStartTime",
+        "setAutoStartTime [seconds, 0 to disable]",
+        "Change how long the lobby waits before starting the race on its own",
+        set_auto_start_time,
+    ));
+    registry.register(Command::new(
+        "setAutoStartMinPlayers",
+        "setAutoStartMinPlayers [players, 1 or more]",
+        "Change how many players are needed for the auto start timer",
+        set_auto_start_min_players,
+    ));
+    registry.register(Command::new(
+        "setStageRotationMode",
+        "setStageRotationMode [None/Sequenced/Random]",
+        "Change how the stage changes after every race",
+        set_stage_rotation_mode,
+    ));
+    registry.register(Command::new(
+        "setVoteRatio",
+        "setVoteRatio [ratio, 0 to 1]",
+        "Change how many clients need to vote to go back to the lobby",
+        set_vote_ratio,
+    ));
+    registry.register(Command::new(
+        "setDisqualificationTime",
+        "setDisqualificationTime [seconds, 0 to disable]",
+        "Change how long a player can go without passing a checkpoint",
+        set_disqualification_time,
+    ));
+    registry.register(Command::new(
+        "stop",
+        "stop",
+        "Disconnect everyone and close the server",
+        stop,
+    ));
+}
+
+fn say(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    server.chat_all(&args.text()?);
+    output.line("Chat message sent");
+    Ok(())
+}
+
+fn clients(server: &mut Server, _: &Args, output: &mut dyn Output) -> CommandResult {
+    for line in server.client_list() {
+        output.line(&line);
+    }
+    Ok(())
+}
+
+fn players(server: &mut Server, _: &Args, output: &mut dyn Output) -> CommandResult {
+    for line in server.player_list() {
+        output.line(&line);
+    }
+    Ok(())
+}
+
+///The one client with a name containing `name`, with its adress
+fn find_client(server: &Server, name: &str) -> Result<(String, SocketAddr), CommandError> {
+    let name = name.to_lowercase();
+
+    let mut matching: Vec<_> = server
+        .clients()
+        .iter()
+        .filter(|e| e.name.to_lowercase().contains(&name))
+        .map(|e| (e.name.clone(), e.connection))
+        .collect();
+
+    match matching.len() {
+        0 => Err(format!("No clients found with a name containing \"{name}\"").into()),
+        1 => Ok(matching.remove(0)),
+        _ => {
+            let names: Vec<String> = matching.into_iter().map(|e| e.0).collect();
+            Err(format!("More than one client found: {}", names.join(", ")).into())
+        }
+    }
+}
+
+///The client name a command is about, the whole text unless it's in quotes
+fn client_name(args: &Args) -> Result<String, CommandError> {
+    match args.name(0)? {
+        (name, next) if next == args.len() => Ok(name),
+        _ => args.text(),
+    }
+}
+
+///The reason given from `index` on, or `default` if there's none
+fn reason(args: &Args, index: usize, default: &str) -> String {
+    match args.rest(index) {
+        reason if reason.is_empty() => default.to_owned(),
+        reason => reason,
+    }
+}
+
+///How long a ban lasts, None if it's forever
+fn ban_duration(args: &Args, index: usize) -> Result<Option<Duration>, CommandError> {
+    let minutes: u64 = args.get(index)?;
+    if minutes == 0 {
+        return Ok(None);
+    }
+
+    match minutes.checked_mul(60) {
+        Some(seconds) => Ok(Some(Duration::from_secs(seconds))),
+        None => Err(format!("{minutes} minutes is too long, use 0 to ban for ever").into()),
+    }
+}
+
+fn kick(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    let (name, next) = args.name(0)?;
+    let (kicked, addr) = find_client(server, &name)?;
+    server.kick(addr, &reason(args, next, "Kicked by the server"));
+    output.line(&format!("Kicked {kicked}"));
+    Ok(())
+}
+
+fn ban(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    let (name, next) = args.name(0)?;
+    let (banned, addr) = find_client(server, &name)?;
+    let duration = ban_duration(args, next)?;
+
+    server.ban_client(
+        addr,
+        &reason(args, next + 1, "Banned by the server"),
+        duration,
+    )?;
+    output.line(&format!("Banned {banned}"));
+    Ok(())
+}
+
+fn ban_ip(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    let target = BanTarget::parse(&args.get::<String>(0)?)?;
+    if let BanTarget::Guid(_) = target {
+        return Err(CommandError::Usage);
+    }
+    let duration = ban_duration(args, 1)?;
+
+    server.ban(target, &reason(args, 2, "Banned by the server"), duration)?;
+    output.line(&format!("Banned {}", args.words()[0]));
+    Ok(())
+}
+
+fn unban(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    let target = BanTarget::parse(&args.only::<String>()?)?;
+    server.unban(&target)?;
+    output.line(&format!("Unbanned {}", args.words()[0]));
+    Ok(())
+}
+
+fn bans(server: &mut Server, _: &Args, output: &mut dyn Output) -> CommandResult {
+    for line in server.ban_list() {
+        output.line(&line);
+    }
+    Ok(())
+}
+
+fn mute(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    let (muted, addr) = find_client(server, &client_name(args)?)?;
+    server.set_muted(addr, true)?;
+    output.line(&format!("Muted {muted}"));
+    Ok(())
+}
+
+fn unmute(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    let (unmuted, addr) = find_client(server, &client_name(args)?)?;
+    server.set_muted(addr, false)?;
+    output.line(&format!("Unmuted {unmuted}"));
+    Ok(())
+}
+
+fn return_to_lobby(server: &mut Server, _: &Args, _: &mut dyn Output) -> CommandResult {
+    if !server.in_race() {
+        return Err("The server is already in the lobby".to_owned().into());
+    }
+
+    server.return_to_lobby();
+    Ok(())
+}
+
+fn force_start(server: &mut Server, _: &Args, output: &mut dyn Output) -> CommandResult {
+    if server.in_race() {
+        return Err("The race is already in progress".to_owned().into());
+    }
+
+    output.line("The race has been forcefully started");
+    server.load_race();
+    Ok(())
+}
+
+fn show_settings(server: &mut Server, _: &Args, output: &mut dyn Output) -> CommandResult {
+    let json = serde_json::to_string_pretty(server.match_settings()).map_err(|e| e.to_string())?;
+    for line in json.lines() {
+        output.line(line);
+    }
+    Ok(())
+}
+
+fn motd(server: &mut Server, _: &Args, output: &mut dyn Output) -> CommandResult {
+    output.line(server.motd());
+    Ok(())
+}
+
+fn vote_lobby(server: &mut Server, args: &Args, _: &mut dyn Output) -> CommandResult {
+    server.vote_lobby_from(args.client()?)?;
+    Ok(())
+}
+
+fn login(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    let password = args.text()?;
+    server.login(args.client()?, &password)?;
+    output.line("You are now an admin");
+    Ok(())
+}
+
+fn reload_settings(server: &mut Server, _: &Args, output: &mut dyn Output) -> CommandResult {
+    server.reload_settings()?;
+    output.line("Match settings reloaded");
+    Ok(())
+}
+
+fn reload_motd(server: &mut Server, _: &Args, output: &mut dyn Output) -> CommandResult {
+    server.reload_motd()?;
+    output.line("Message of the day reloaded");
+    Ok(())
+}
+
+fn set_stage(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    let stage: i32 = args.only()?;
+    server.change_settings(|e| e.stage_id = stage)?;
+    output.line(&format!("Stage set to {stage}"));
+    Ok(())
+}
+
+fn set_laps(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    let laps: i32 = args.only()?;
+    server.change_settings(|e| e.laps = laps)?;
+    output.line(&format!("Laps set to {laps}"));
+    Ok(())
+}
+
+fn set_auto_start_time(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    let time: i32 = args.only()?;
+    if time < 0 {
+        return Err(CommandError::Usage);
+    }
+
+    server.change_settings(|e| e.auto_start_time = time)?;
+    output.line(&format!("Auto start time set to {time}"));
+    Ok(())
+}
+
+fn set_auto_start_min_players(
+    server: &mut Server,
+    args: &Args,
+    output: &mut dyn Output,
+) -> CommandResult {
+    let players: i32 = args.only()?;
+    if players < 1 {
+        return Err(CommandError::Usage);
+    }
+
+    server.change_settings(|e| e.auto_start_min_players = players)?;
+    output.line(&format!("Auto start minimum players set to {players}"));
+    Ok(())
+}
+
+fn set_stage_rotation_mode(
+    server: &mut Server,
+    args: &Args,
+    output: &mut dyn Output,
+) -> CommandResult {
+    let arg: String = args.only()?;
+    //Either the name or the number C# uses
+    let mode = match arg.to_lowercase().as_str() {
+        "none" => StageRotationMode::None,
+        "sequenced" => StageRotationMode::Sequenced,
+        "random" => StageRotationMode::Random,
+        number => {
+            let number: i32 = number.parse().map_err(|_| CommandError::Usage)?;
+            StageRotationMode::try_from(number).map_err(|_| CommandError::Usage)?
+        }
+    };
+
+    server.change_settings(|e| e.stage_rotation_mode = mode)?;
+    output.line(&format!("Stage rotation mode set to {mode:?}"));
+    Ok(())
+}
+
+fn set_vote_ratio(server: &mut Server, args: &Args, output: &mut dyn Output) -> CommandResult {
+    let ratio: f32 = args.only()?;
+    server.change_settings(|e| e.vote_ratio = ratio)?;
+    output.line(&format!("Vote ratio set to {ratio}"));
+    Ok(())
+}
+
+fn set_disqualification_time(
+    server: &mut Server,
+    args: &Args,
+    output: &mut dyn Output,
+) -> CommandResult {
+    let time: i32 = args.only()?;
+    server.change_settings(|e| e.disqualification_time = time)?;
+    output.line(&format!("Disqualification time set to {time}"));
+    Ok(())
+}
+
+fn stop(server: &mut Server, _: &Args, output: &mut dyn Output) -> CommandResult {
+    output.line("Stopping the server");
+    server.shutdown();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Args {
+        Args::new(
+            line.split_whitespace().map(|e| e.to_owned()).collect(),
+            None,
+        )
+    }
+
+    #[test]
+    fn name_is_one_word_without_quotes() {
+        let args = args("Sanic 10 too fast");
+        assert_eq!(args.name(0).unwrap(), ("Sanic".to_owned(), 1));
+        assert_eq!(reason(&args, 2, "none"), "too fast");
+    }
+
+    #[test]
+    fn name_in_quotes_can_have_spaces() {
+        let args = args("\"Sanic Fan 2\" 10 too fast");
+        assert_eq!(args.name(0).unwrap(), ("Sanic Fan 2".to_owned(), 3));
+        assert_eq!(args.get::<u64>(3).unwrap(), 10);
+
+        assert_eq!(
+            self::args("\"Sanic\"").name(0).unwrap(),
+            ("Sanic".to_owned(), 1)
+        );
+    }
+
+    #[test]
+    fn name_needs_closed_quotes() {
+        assert!(args("\"Sanic Fan").name(0).is_err());
+        assert!(args("\"\"").name(0).is_err());
+        assert!(args("").name(0).is_err());
+    }
+
+    #[test]
+    fn client_name_is_the_whole_text_unless_quoted() {
+        assert_eq!(client_name(&args("Sanic Fan")).unwrap(), "Sanic Fan");
+        assert_eq!(client_name(&args("\"Sanic Fan\"")).unwrap(), "Sanic Fan");
+    }
+
+    #[test]
+    fn ban_duration_is_in_minutes() {
+        assert!(ban_duration(&args("0"), 0).unwrap().is_none());
+        assert_eq!(
+            ban_duration(&args("90"), 0).unwrap(),
+            Some(Duration::from_secs(90 * 60))
+        );
+        assert!(ban_duration(&args("-1"), 0).is_err());
+        assert!(ban_duration(&args(""), 0).is_err());
+    }
+
+    #[test]
+    fn ban_duration_too_long_is_refused() {
+        let minutes = (u64::MAX / 60 + 1).to_string();
+        assert!(matches!(
+            ban_duration(&args(&minutes), 0),
+            Err(CommandError::Failed(_))
+        ));
+        let minutes = (u64::MAX / 60).to_string();
+        assert!(ban_duration(&args(&minutes), 0).is_ok());
+    }
+}
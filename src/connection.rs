@@ -0,0 +1,678 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    buffer::{Buffer, DEFAULT_MTU, HEADER_SIZE},
+    channel::{ReceiveChannel, SendChannel},
+    headers::{Delivery, Header},
+    stream::{DecodeError, ReadResult, Stream},
+    to_byte,
+};
+
+///How often we ping a connection to check it's still there
+pub const PING_INTERVAL: Duration = Duration::from_secs(4);
+
+///How long a connection can stay silent before we give up on it
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(25);
+
+///How long a disconnected connection lingers so late packets don't look like a new peer
+pub const DISCONNECT_LINGER: Duration = Duration::from_secs(1);
+
+///Lidgren starts counting fragment groups over after this
+const FRAGMENT_GROUPS: u32 = 65534;
+
+///Biggest message we're willing to put back together from fragments
+const MAX_FRAGMENTED_SIZE: usize = 1 << 20;
+
+///How many messages a connection can have half-received at once
+const MAX_FRAGMENT_GROUPS: usize = 16;
+
+///How long we wait for the rest of a fragmented message before dropping what we have
+const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+///Where a connection is in its life, every adress goes through these in order
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConnectionState {
+    ///Sent Connect, we answered with ConnectResponse and are waiting for ConnectionEstablished
+    Connecting,
+    ///Handshake is done, the peer can send and receive game messages
+    Connected,
+    ///We or the peer said goodbye, anything else it sends is ignored
+    Disconnecting,
+    ///Nothing left to do, the connection gets removed
+    Gone,
+}
+
+///The fragments of one big message, filled in as they arrive
+struct FragmentGroup {
+    data: Vec<u8>,
+    received: Vec<bool>,
+    started: Instant,
+}
+
+///One side of a Lidgren connection, keeps track of everything needed to make messages reliable
+pub struct Connection {
+    pub origin: SocketAddr,
+    pub state: ConnectionState,
+
+    //Heartbeat
+    last_heard: Instant,
+    last_ping: Option<Instant>,
+    ping_number: u8,
+    //When the ping we're waiting on a pong for was sent
+    ping_sent: Option<Instant>,
+    disconnected_at: Option<Instant>,
+
+    //Smoothed round trip time, None until the first pong comes back
+    pub rtt: Option<Duration>,
+    //How far ahead the peer's clock is from ours in seconds, None until the first pong comes back
+    pub clock_offset: Option<f32>,
+
+    //Biggest datagram we know gets through, bigger messages are fragmented
+    pub mtu: usize,
+    //Size of the MTU probe we're waiting to hear back about
+    mtu_probe: Option<usize>,
+    fragment_group: u32,
+    fragments: HashMap<u32, FragmentGroup>,
+
+    //Outgoing, one channel for every user message type
+    senders: HashMap<u8, SendChannel>,
+
+    //Waiting to be packed into the next datagrams
+    acks: Vec<(u8, u16)>,
+    outbox: Vec<Vec<u8>>,
+
+    //Incoming, one channel for every user message type
+    receivers: HashMap<u8, ReceiveChannel>,
+}
+
+impl Connection {
+    pub fn new(origin: SocketAddr) -> Self {
+        Connection {
+            origin,
+            state: ConnectionState::Connecting,
+            last_heard: Instant::now(),
+            last_ping: None,
+            ping_number: 0,
+            ping_sent: None,
+            disconnected_at: None,
+            rtt: None,
+            clock_offset: None,
+            mtu: DEFAULT_MTU,
+            mtu_probe: None,
+            fragment_group: 0,
+            fragments: HashMap::new(),
+            senders: HashMap::new(),
+            acks: vec![],
+            outbox: vec![],
+            receivers: HashMap::new(),
+        }
+    }
+
+    ///The peer sent us something, so it's still alive
+    pub fn heard(&mut self) {
+        self.last_heard = Instant::now();
+    }
+
+    ///The handshake finished
+    pub fn establish(&mut self) {
+        if self.state == ConnectionState::Connecting {
+            self.state = ConnectionState::Connected;
+        }
+    }
+
+    ///Stop talking to the peer, it is removed once the linger time is over
+    pub fn disconnect(&mut self) {
+        if let ConnectionState::Connecting | ConnectionState::Connected = self.state {
+            self.state = ConnectionState::Disconnecting;
+            self.disconnected_at = Some(Instant::now());
+        }
+    }
+
+    ///The peer hasn't sent anything for too long
+    pub fn timed_out(&self) -> bool {
+        self.is_active() && self.last_heard.elapsed() > CONNECTION_TIMEOUT
+    }
+
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self.state,
+            ConnectionState::Connecting | ConnectionState::Connected
+        )
+    }
+
+    ///Move on to Gone once a disconnecting connection lingered long enough
+    pub fn linger(&mut self) {
+        if let Some(time) = self.disconnected_at {
+            if time.elapsed() > DISCONNECT_LINGER {
+                self.state = ConnectionState::Gone;
+            }
+        }
+    }
+
+    ///Returns the number of the next ping if it's time to send one, the first one goes out right after the handshake
+    pub fn ping(&mut self) -> Option<u8> {
+        if self.state != ConnectionState::Connected {
+            return None;
+        }
+        if self.last_ping.is_some_and(|e| e.elapsed() < PING_INTERVAL) {
+            return None;
+        }
+
+        self.last_ping = Some(Instant::now());
+        self.ping_sent = self.last_ping;
+        self.ping_number = self.ping_number.wrapping_add(1);
+        Some(self.ping_number)
+    }
+
+    ///The peer answered a ping, measure the round trip and how far its clock is from ours.
+    ///`local_time` is our clock when the pong arrived, in the same unit as `remote_time`.
+    pub fn pong(&mut self, number: u8, remote_time: f32, local_time: f32) {
+        //Answer to an older ping, or one we already got
+        if number != self.ping_number {
+            return;
+        }
+        let Some(sent) = self.ping_sent.take() else {
+            return;
+        };
+
+        let sample = sent.elapsed();
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => rtt.mul_f32(0.7) + sample.mul_f32(0.3),
+            None => sample,
+        });
+
+        //The peer read its clock about halfway through the round trip
+        let offset = remote_time + sample.as_secs_f32() / 2.0 - local_time;
+        self.clock_offset = Some(match self.clock_offset {
+            Some(clock_offset) => clock_offset * 0.7 + offset * 0.3,
+            None => offset,
+        });
+    }
+
+    ///We sent a datagram of `size` bytes to see if bigger ones get through
+    pub fn probe_mtu(&mut self, size: usize) {
+        self.mtu_probe = Some(size);
+    }
+
+    ///The peer got a probe of `size` bytes, anything bigger than what we probed is ignored.
+    ///Returns whether the MTU changed.
+    pub fn expand_mtu(&mut self, size: usize) -> bool {
+        //We didn't ask, or already heard back
+        let Some(probe) = self.mtu_probe.take() else {
+            return false;
+        };
+
+        let mtu = self.mtu.max(size.min(probe));
+        let changed = mtu != self.mtu;
+        self.mtu = mtu;
+        changed
+    }
+
+    ///How long we wait for an ack before sending a message again (same formula as Lidgren)
+    pub fn resend_delay(&self) -> Duration {
+        let rtt = self.rtt.unwrap_or(Duration::from_millis(100));
+        Duration::from_millis(25) + rtt.mul_f32(2.1)
+    }
+
+    ///Send a message with the next datagram
+    pub fn send(&mut self, message: Vec<u8>) {
+        self.outbox.push(message);
+    }
+
+    ///Send a user message the way its channel delivers them.
+    ///Messages bigger than the MTU are split into fragments first.
+    ///Reliable messages that don't fit in their channel's window wait until it has room.
+    pub fn send_user(&mut self, buffer: &Buffer) {
+        let header = buffer.payload[0];
+        let Some(delivery) = Delivery::of(header) else {
+            return;
+        };
+
+        let messages = match buffer.is_oversize(self.mtu) {
+            true => {
+                self.fragment_group = self.fragment_group % FRAGMENT_GROUPS + 1;
+                buffer.fragments(self.fragment_group, self.mtu)
+            }
+            false => vec![buffer.message()],
+        };
+
+        let channel = self.senders.entry(header).or_default();
+        for message in messages {
+            match delivery {
+                Delivery::Unreliable => self.outbox.push(message),
+                Delivery::Sequenced => self.outbox.push(channel.sequence(message)),
+                _ => self.outbox.extend(channel.send_reliable(message)),
+            }
+        }
+    }
+
+    ///React to the other side telling us it got one of our messages
+    pub fn acknowledge(&mut self, header: u8, sequence: u16) {
+        if let Some(channel) = self.senders.get_mut(&header) {
+            channel.acknowledge(sequence);
+        }
+    }
+
+    ///Send again the messages that waited too long for an ack, plus any that now fit in their window
+    pub fn resend(&mut self) {
+        if !self.is_active() {
+            return;
+        }
+
+        let delay = self.resend_delay();
+        for channel in self.senders.values_mut() {
+            self.outbox.extend(channel.resend(delay));
+        }
+    }
+
+    ///Remember to acknowledge a message, the acks all go out together on the next flush
+    pub fn ack(&mut self, header: u8, sequence: u16) {
+        self.acks.push((header, sequence));
+    }
+
+    ///Pack the pending acks and messages into as few datagrams as the MTU allows
+    pub fn flush(&mut self) -> Vec<Vec<u8>> {
+        let mut messages = vec![];
+
+        //Every ack takes 3 bytes: the header of the message and its sequence number
+        let per_message = (self.mtu - HEADER_SIZE) / 3;
+        for acks in self.acks.chunks(per_message) {
+            let mut buffer = Buffer::default();
+            for (header, sequence) in acks {
+                buffer.write_byte(*header);
+                buffer.write_byte(to_byte(usize::from(*sequence)));
+                buffer.write_byte(to_byte(usize::from(*sequence >> 8)));
+            }
+            buffer.write_header(Header::Acknowledge);
+            messages.push(buffer.message());
+        }
+        self.acks.clear();
+        messages.append(&mut self.outbox);
+
+        let mut datagrams: Vec<Vec<u8>> = vec![];
+        for message in messages {
+            match datagrams.last_mut() {
+                Some(datagram) if datagram.len() + message.len() <= self.mtu => {
+                    datagram.extend(message)
+                }
+                _ => datagrams.push(message),
+            }
+        }
+
+        datagrams
+    }
+
+    ///Run an incoming user message through its channel.
+    ///Returns every message that can be handled now, oldest first.
+    pub fn receive(&mut self, stream: Stream) -> Vec<Stream> {
+        let Some(delivery) = Delivery::of(stream.header_byte) else {
+            return vec![];
+        };
+
+        self.receivers
+            .entry(stream.header_byte)
+            .or_default()
+            .receive(delivery, stream)
+    }
+
+    ///Put a fragment where it belongs in its message.
+    ///Returns the whole message once the last missing fragment arrived.
+    pub fn reassemble(&mut self, mut stream: Stream) -> ReadResult<Option<Stream>> {
+        let group: u32 = stream
+            .read_varint()?
+            .try_into()
+            .map_err(|_| DecodeError::BadFragment)?;
+        if !(1..=FRAGMENT_GROUPS).contains(&group) {
+            return Err(DecodeError::BadFragment);
+        }
+        let total_bits = stream.read_varint()?;
+        let chunk_size = stream.read_varint()?;
+        let number = stream.read_varint()?;
+
+        let total_size = total_bits.div_ceil(8);
+        if chunk_size == 0 || total_size > MAX_FRAGMENTED_SIZE {
+            return Err(DecodeError::BadFragment);
+        }
+
+        let chunk = stream.rest();
+        let start = number
+            .checked_mul(chunk_size)
+            .ok_or(DecodeError::BadFragment)?;
+        if start + chunk.len() > total_size || chunk.len() > chunk_size {
+            return Err(DecodeError::BadFragment);
+        }
+
+        //Forget messages whose other fragments never came
+        self.fragments
+            .retain(|_, e| e.started.elapsed() < FRAGMENT_TIMEOUT);
+        if !self.fragments.contains_key(&group) && self.fragments.len() >= MAX_FRAGMENT_GROUPS {
+            return Err(DecodeError::BadFragment);
+        }
+
+        let fragments = self
+            .fragments
+            .entry(group)
+            .or_insert_with(|| FragmentGroup {
+                data: vec![0; total_size],
+                received: vec![false; total_size.div_ceil(chunk_size)],
+                started: Instant::now(),
+            });
+        if fragments.data.len() != total_size || number >= fragments.received.len() {
+            return Err(DecodeError::BadFragment);
+        }
+
+        fragments.data[start..start + chunk.len()].copy_from_slice(chunk);
+        fragments.received[number] = true;
+
+        if fragments.received.iter().all(|e| *e) {
+            let fragments = self.fragments.remove(&group).unwrap();
+            return Ok(Some(stream.assembled(fragments.data)));
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn connection() -> Connection {
+        Connection::new(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 25000))
+    }
+
+    ///Pretend ping `number` went out `ago` ago
+    fn pinged(connection: &mut Connection, number: u8, ago: Duration) {
+        connection.ping_number = number;
+        connection.ping_sent = Some(Instant::now() - ago);
+    }
+
+    #[test]
+    fn pong_measures_rtt_and_clock_offset() {
+        let mut connection = connection();
+        pinged(&mut connection, 1, Duration::from_millis(200));
+        connection.pong(1, 50.0, 10.0);
+
+        let rtt = connection.rtt.unwrap();
+        assert!(rtt >= Duration::from_millis(200) && rtt < Duration::from_millis(300));
+        let offset = connection.clock_offset.unwrap();
+        assert!((offset - (40.0 + rtt.as_secs_f32() / 2.0)).abs() < 0.001);
+
+        //Later pongs are smoothed into what we already measured
+        pinged(&mut connection, 2, Duration::from_millis(100));
+        connection.pong(2, 80.0, 20.0);
+
+        let smoothed = connection.rtt.unwrap();
+        let sample = (smoothed.as_secs_f32() - rtt.as_secs_f32() * 0.7) / 0.3;
+        assert!((0.1..0.2).contains(&sample));
+        let expected = offset * 0.7 + (60.0 + sample / 2.0) * 0.3;
+        assert!((connection.clock_offset.unwrap() - expected).abs() < 0.001);
+    }
+
+    #[test]
+    fn pong_for_another_ping_is_ignored() {
+        let mut connection = connection();
+        pinged(&mut connection, 2, Duration::from_millis(100));
+
+        connection.pong(1, 50.0, 10.0);
+        assert!(connection.rtt.is_none());
+
+        connection.pong(2, 50.0, 10.0);
+        let rtt = connection.rtt;
+        assert!(rtt.is_some());
+
+        //The same pong again
+        connection.pong(2, 99.0, 10.0);
+        assert_eq!(connection.rtt, rtt);
+    }
+
+    fn origin() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 25000)
+    }
+
+    ///A fragment with whatever header the test wants, valid or not
+    fn fragment(
+        group: usize,
+        total_bits: usize,
+        chunk_size: usize,
+        number: usize,
+        chunk: &[u8],
+    ) -> Stream {
+        let mut buffer = Buffer::default();
+        buffer.write_varint(group);
+        buffer.write_varint(total_bits);
+        buffer.write_varint(chunk_size);
+        buffer.write_varint(number);
+        for byte in chunk {
+            buffer.write_byte(*byte);
+        }
+        buffer.write_header(Header::UserReliableOrdered1);
+        buffer.payload[1] |= 1;
+        Stream::new(&buffer.message(), origin()).unwrap()
+    }
+
+    ///A message big enough to be split, with the fragments Buffer makes of it
+    fn split(group: u32, size: usize) -> (Vec<u8>, Vec<Stream>) {
+        let mut buffer = Buffer::default();
+        for byte in 0..size {
+            buffer.write_byte(to_byte(byte % 251));
+        }
+        buffer.write_header(Header::UserReliableOrdered1);
+
+        let fragments = buffer
+            .fragments(group, DEFAULT_MTU)
+            .iter()
+            .map(|e| Stream::new(e, origin()).unwrap())
+            .collect();
+        (buffer.payload[HEADER_SIZE..].to_vec(), fragments)
+    }
+
+    #[test]
+    fn split_message_is_put_back_together() {
+        let mut connection = connection();
+        let (data, fragments) = split(1, 5000);
+        let last = fragments.len() - 1;
+
+        for (number, fragment) in fragments.into_iter().enumerate() {
+            let whole = connection.reassemble(fragment).unwrap();
+            match number == last {
+                true => {
+                    let whole = whole.unwrap();
+                    assert!(!whole.fragment);
+                    assert_eq!(whole.data, data);
+                }
+                false => assert!(whole.is_none()),
+            }
+        }
+        assert!(connection.fragments.is_empty());
+    }
+
+    #[test]
+    fn fragments_can_come_out_of_order_and_twice() {
+        let mut connection = connection();
+        let (data, mut fragments) = split(2, 5000);
+        fragments.reverse();
+        let first = fragments.pop().unwrap();
+
+        for fragment in fragments.iter() {
+            assert!(connection.reassemble(fragment.clone()).unwrap().is_none());
+            assert!(connection.reassemble(fragment.clone()).unwrap().is_none());
+        }
+        let whole = connection.reassemble(first).unwrap().unwrap();
+        assert_eq!(whole.data, data);
+    }
+
+    #[test]
+    fn group_must_be_in_the_lidgren_range() {
+        let mut connection = connection();
+        assert!(connection.reassemble(fragment(0, 16, 1, 0, &[1])).is_err());
+        assert!(connection
+            .reassemble(fragment(65535, 16, 1, 0, &[1]))
+            .is_err());
+        assert!(connection
+            .reassemble(fragment(65534, 16, 1, 0, &[1]))
+            .is_ok());
+    }
+
+    #[test]
+    fn fragments_that_dont_fit_are_rejected() {
+        let mut connection = connection();
+
+        //Chunk size of 0
+        assert!(connection.reassemble(fragment(1, 16, 0, 0, &[])).is_err());
+        //Chunk number past the end of the message
+        assert!(connection.reassemble(fragment(1, 16, 1, 2, &[1])).is_err());
+        assert!(connection
+            .reassemble(fragment(1, 16, 1, usize::MAX >> 36, &[1]))
+            .is_err());
+        //Chunk bigger than its chunk size
+        assert!(connection
+            .reassemble(fragment(1, 32, 1, 0, &[1, 2]))
+            .is_err());
+        //Bigger than we're willing to put together
+        let too_big = (MAX_FRAGMENTED_SIZE + 1) * 8;
+        assert!(connection
+            .reassemble(fragment(1, too_big, 1, 0, &[1]))
+            .is_err());
+        assert!(connection.fragments.is_empty());
+
+        //Another fragment of the group that says the message has a different size
+        assert!(connection
+            .reassemble(fragment(1, 24, 1, 0, &[1]))
+            .unwrap()
+            .is_none());
+        assert!(connection.reassemble(fragment(1, 32, 1, 1, &[2])).is_err());
+        let whole = connection.reassemble(fragment(1, 24, 1, 1, &[2])).unwrap();
+        assert!(whole.is_none());
+        let whole = connection.reassemble(fragment(1, 24, 1, 2, &[3])).unwrap();
+        assert_eq!(whole.unwrap().data, [1, 2, 3]);
+    }
+
+    #[test]
+    fn open_groups_are_limited() {
+        let mut connection = connection();
+        for group in 1..=MAX_FRAGMENT_GROUPS {
+            assert!(connection
+                .reassemble(fragment(group, 16, 1, 0, &[1]))
+                .unwrap()
+                .is_none());
+        }
+
+        let group = MAX_FRAGMENT_GROUPS + 1;
+        assert!(connection
+            .reassemble(fragment(group, 16, 1, 0, &[1]))
+            .is_err());
+
+        //Groups that are already open can still be finished
+        let whole = connection.reassemble(fragment(1, 16, 1, 1, &[2])).unwrap();
+        assert_eq!(whole.unwrap().data, [1, 2]);
+        assert!(connection
+            .reassemble(fragment(group, 16, 1, 0, &[1]))
+            .is_ok());
+    }
+
+    #[test]
+    fn incomplete_groups_are_dropped_after_a_while() {
+        let mut connection = connection();
+        for group in 1..=MAX_FRAGMENT_GROUPS {
+            connection
+                .reassemble(fragment(group, 16, 1, 0, &[1]))
+                .unwrap();
+        }
+        let started = Instant::now() - FRAGMENT_TIMEOUT;
+        for group in connection.fragments.values_mut() {
+            group.started = started;
+        }
+
+        let group = MAX_FRAGMENT_GROUPS + 1;
+        assert!(connection
+            .reassemble(fragment(group, 16, 1, 0, &[1]))
+            .unwrap()
+            .is_none());
+        assert_eq!(connection.fragments.len(), 1);
+
+        //The rest of a dropped group starts over instead of finishing it
+        assert!(connection
+            .reassemble(fragment(1, 16, 1, 1, &[2]))
+            .unwrap()
+            .is_none());
+    }
+
+    ///An unreliable message with `size` bytes of data
+    fn unreliable(size: usize) -> Vec<u8> {
+        let mut buffer = Buffer::default();
+        for _ in 0..size {
+            buffer.write_byte(1);
+        }
+        buffer.write_header(Header::UserUnreliable);
+        buffer.message()
+    }
+
+    #[test]
+    fn flush_packs_messages_under_the_mtu() {
+        let mut connection = connection();
+        for _ in 0..3 {
+            connection.send(unreliable(100));
+        }
+
+        let datagrams = connection.flush();
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].len(), 3 * (HEADER_SIZE + 100));
+        assert_eq!(Stream::parse(&datagrams[0], origin()).len(), 3);
+
+        //Everything went out
+        assert!(connection.flush().is_empty());
+    }
+
+    #[test]
+    fn flush_starts_a_new_datagram_when_the_next_message_doesnt_fit() {
+        let mut connection = connection();
+        for _ in 0..3 {
+            connection.send(unreliable(600));
+        }
+        //Bigger than the MTU on its own, it still gets a datagram
+        connection.send(unreliable(DEFAULT_MTU));
+
+        let datagrams = connection.flush();
+        let sizes: Vec<usize> = datagrams.iter().map(|e| e.len()).collect();
+        assert_eq!(
+            sizes,
+            [
+                2 * (HEADER_SIZE + 600),
+                HEADER_SIZE + 600,
+                HEADER_SIZE + DEFAULT_MTU
+            ]
+        );
+        assert!(datagrams.iter().take(2).all(|e| e.len() <= DEFAULT_MTU));
+    }
+
+    #[test]
+    fn flush_sends_acks_first_and_splits_them_by_the_mtu() {
+        let mut connection = connection();
+        connection.send(unreliable(10));
+        connection.ack(67, 1000);
+
+        let datagrams = connection.flush();
+        assert_eq!(datagrams.len(), 1);
+        let streams = Stream::parse(&datagrams[0], origin());
+        let acks = streams[0].as_ref().unwrap();
+        assert_eq!(acks.header_byte, Header::Acknowledge as u8);
+        assert_eq!(acks.data, [67, to_byte(1000), to_byte(1000 >> 8)]);
+        assert_eq!(streams[1].as_ref().unwrap().data.len(), 10);
+
+        //More acks than fit in one datagram
+        let per_message = (DEFAULT_MTU - HEADER_SIZE) / 3;
+        for sequence in 0..=per_message {
+            connection.ack(67, sequence.try_into().unwrap());
+        }
+        let datagrams = connection.flush();
+        assert_eq!(datagrams.len(), 2);
+        assert!(datagrams.iter().all(|e| e.len() <= DEFAULT_MTU));
+        assert_eq!(datagrams[1].len(), HEADER_SIZE + 3);
+    }
+}
@@ -4,29 +4,174 @@ use std::{
     time::{Duration, Instant},
 };
 
-use crate::game::CtrlType;
+use crate::game::{AllowedTiers, CtrlType, StageRotationMode, TierRotationMode};
 
 #[derive(Deserialize, Debug)]
 pub struct ServerConfig {
+    #[serde(default = "server_name")]
+    pub name: String,
     pub public: bool,
     pub servers: Vec<String>,
     pub ip: String,
     pub port: i32,
     pub max_players: u8,
+    ///How many stages the game has, used when rotating stages
+    #[serde(default = "stage_count")]
+    pub stage_count: i32,
+    ///Kick clients that are still loading when the stage loading timeout is over
+    #[serde(default)]
+    pub kick_slow_loaders: bool,
+    ///GUIDs of the clients allowed to change the match settings.
+    ///Every client sees the GUIDs of the others, so prefer admin_password on public servers.
+    #[serde(default)]
+    pub admins: Vec<String>,
+    ///Lets clients become admins with /login, empty to only allow the GUIDs in admins
+    #[serde(default)]
+    pub admin_password: String,
+    ///Chat messages containing any of these are blocked, admins can still send them
+    #[serde(default)]
+    pub chat_filter: Vec<String>,
+    ///How many chat messages a client can send every 10 seconds, 0 for no limit. Admins have none.
+    #[serde(default)]
+    pub chat_rate_limit: usize,
     pub enabled_connections: Vec<u8>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+fn server_name() -> String {
+    "Sanicball Rust Server".to_owned()
+}
+
+///The C# STAGE_COUNT
+fn stage_count() -> i32 {
+    5
+}
+
+///The C# MatchSettings, field names match C# so the game can read it from a SettingsChangedMessage.
+///The snake_case names are still accepted so older match.json files load.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "PascalCase")]
 pub struct MatchConfig {
+    #[serde(alias = "stage_id")]
     pub stage_id: i32,
+    #[serde(alias = "laps")]
     pub laps: i32,
+    #[serde(rename = "AICount", alias = "ai_count")]
     pub ai_count: i32,
+    #[serde(rename = "AISkill", alias = "ai_skill")]
     pub ai_skill: i32,
+    ///Character of every AI in order, C# crashes if this is missing
+    #[serde(rename = "aiCharacters", default = "ai_characters")]
+    pub ai_characters: String,
+    #[serde(alias = "auto_start_time")]
     pub auto_start_time: i32,
+    #[serde(alias = "auto_start_min_players")]
     pub auto_start_min_players: i32,
+    #[serde(alias = "auto_return_time")]
     pub auto_return_time: i32,
+    #[serde(alias = "vote_ratio")]
     pub vote_ratio: f32,
-    pub stage_rotation_mode: i32,
+    #[serde(alias = "stage_rotation_mode")]
+    pub stage_rotation_mode: StageRotationMode,
+    #[serde(alias = "allowed_tiers", default)]
+    pub allowed_tiers: AllowedTiers,
+    #[serde(alias = "tier_rotation_mode", default)]
+    pub tier_rotation_mode: TierRotationMode,
+    ///Seconds a player can go without passing a checkpoint before being disqualified, 0 to never disqualify
+    #[serde(alias = "disqualification_time", default = "disqualification_time")]
+    pub disqualification_time: i32,
+}
+
+impl MatchConfig {
+    ///Check the settings make sense before using them, returns what's wrong if they don't
+    pub fn validate(&self, stage_count: i32) -> Result<(), String> {
+        if self.laps <= 0 {
+            return Err(format!("Laps must be above 0, got {}", self.laps));
+        }
+        if !(0..stage_count).contains(&self.stage_id) {
+            return Err(format!(
+                "Stage must be between 0 and {}, got {}",
+                stage_count - 1,
+                self.stage_id
+            ));
+        }
+        let timers = [
+            ("Auto start time", self.auto_start_time),
+            ("Auto return time", self.auto_return_time),
+            ("Disqualification time", self.disqualification_time),
+        ];
+        for (name, time) in timers {
+            if time < 0 {
+                return Err(format!("{name} can't be negative, got {time}"));
+            }
+        }
+        if !(0.0..=1.0).contains(&self.vote_ratio) {
+            return Err(format!(
+                "Vote ratio must be between 0 and 1, got {}",
+                self.vote_ratio
+            ));
+        }
+        Ok(())
+    }
+}
+
+///The C# default disqualification time
+fn disqualification_time() -> i32 {
+    120
+}
+
+///The AI characters C# uses by default
+fn ai_characters() -> String {
+    "1,2,3,4,5,6,7,8,9,10,11,12".to_owned()
+}
+
+///Sent to games looking for servers on the LAN, the C# ServerInfo
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServerInfo {
+    pub config: ServerInfoConfig,
+    pub players: usize,
+    pub in_race: bool,
+}
+
+///The C# ServerConfig, the game shows these in its server browser
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ServerInfoConfig {
+    pub server_name: String,
+    pub show_on_list: bool,
+    #[serde(rename = "ServerListURLs")]
+    pub server_list_urls: Vec<String>,
+    pub private_port: i32,
+    #[serde(rename = "PublicIP")]
+    pub public_ip: String,
+    pub public_port: i32,
+    pub max_players: u8,
+}
+
+impl ServerInfo {
+    pub fn new(config: &ServerConfig, players: usize, in_race: bool) -> Self {
+        ServerInfo {
+            config: ServerInfoConfig {
+                server_name: config.name.clone(),
+                show_on_list: config.public,
+                server_list_urls: config.servers.clone(),
+                private_port: config.port,
+                public_ip: config.ip.clone(),
+                public_port: config.port,
+                max_players: config.max_players,
+            },
+            players,
+            in_race,
+        }
+    }
+}
+
+///What the game tells us about itself when it connects
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+pub struct ClientInfo {
+    pub version: f32,
+    pub is_testing: bool,
 }
 
 #[derive(Deserialize, Debug)]
@@ -50,6 +195,7 @@ impl Clock {
     }
 }
 
+#[derive(Clone)]
 pub struct Timer {
     pub start: Instant,
     pub running_time: Duration,
@@ -105,9 +251,6 @@ pub struct Settings {
     pub stage_rotation_mode: i32,
 }
 
-#[derive(Clone)]
-pub struct Stopwatch {}
-
 #[derive(Clone)]
 pub struct Client {
     pub guid: Vec<u8>,
@@ -115,7 +258,10 @@ pub struct Client {
     pub connection: SocketAddr,
     pub is_loading: bool,
     pub wants_lobby: bool,
-    pub counter: usize,
+    ///Listed in the config admins, can change the match settings
+    pub is_admin: bool,
+    ///When the chat messages that count towards the rate limit were sent
+    pub chat_times: Vec<Instant>,
 }
 
 #[derive(Clone)]
@@ -125,7 +271,10 @@ pub struct Player {
     pub char_id: i32,
     pub ready_to_race: bool,
     pub is_racing: bool,
-    pub race_timeout: Stopwatch,
+    ///Time since the player last passed a checkpoint
+    pub race_timeout: Timer,
+    ///Everyone was warned this player is about to be disqualified
+    pub timeout_warned: bool,
     pub has_timed_out: bool,
 }
 
@@ -139,8 +288,90 @@ pub struct PlayerPosition {
     pub direction: [f32; 3],
 }
 
+#[derive(Clone, Copy, PartialEq, Debug)]
 pub enum CharacterTier {
     Normal = 0,
     Odd = 1,
-    Hypersonic = 2,
+    Hyperspeed = 2,
+}
+
+///Tier of every character by id, hardcoded like in C# since the game doesn't tell us
+pub const CHARACTER_TIERS: [CharacterTier; 16] = [
+    CharacterTier::Normal,     //Sanic
+    CharacterTier::Normal,     //Knackles
+    CharacterTier::Normal,     //Taels
+    CharacterTier::Normal,     //Ame
+    CharacterTier::Normal,     //Shedew
+    CharacterTier::Normal,     //Roge
+    CharacterTier::Normal,     //Asspio
+    CharacterTier::Odd,        //Big
+    CharacterTier::Odd,        //Aggmen
+    CharacterTier::Odd,        //Chermy
+    CharacterTier::Normal,     //Sulver
+    CharacterTier::Normal,     //Bloze
+    CharacterTier::Normal,     //Vactor
+    CharacterTier::Hyperspeed, //Super Sanic
+    CharacterTier::Odd,        //Metal Sanic
+    CharacterTier::Odd,        //Ogre
+];
+
+impl CharacterTier {
+    ///Tier of a character, None if there's no character with that id
+    pub fn of(character: i32) -> Option<CharacterTier> {
+        let index = usize::try_from(character).ok()?;
+        CHARACTER_TIERS.get(index).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn match_config() -> MatchConfig {
+        serde_json::from_str(
+            r#"{
+                "stage_id": 0,
+                "laps": 2,
+                "ai_count": 7,
+                "ai_skill": 1,
+                "auto_start_time": 60,
+                "auto_start_min_players": 2,
+                "auto_return_time": 15,
+                "vote_ratio": 1.0,
+                "stage_rotation_mode": 0
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn validate_catches_bad_settings() {
+        type Change = fn(&mut MatchConfig);
+        let cases: [(&str, Change, bool); 14] = [
+            ("unchanged", |_| {}, true),
+            ("last stage", |e| e.stage_id = 4, true),
+            ("stage past the end", |e| e.stage_id = 5, false),
+            ("negative stage", |e| e.stage_id = -1, false),
+            ("no laps", |e| e.laps = 0, false),
+            ("no auto start", |e| e.auto_start_time = 0, true),
+            ("negative auto start", |e| e.auto_start_time = -1, false),
+            ("negative auto return", |e| e.auto_return_time = -1, false),
+            ("never disqualify", |e| e.disqualification_time = 0, true),
+            (
+                "negative disqualification",
+                |e| e.disqualification_time = -5,
+                false,
+            ),
+            ("vote ratio 0", |e| e.vote_ratio = 0.0, true),
+            ("vote ratio above 1", |e| e.vote_ratio = 1.5, false),
+            ("negative vote ratio", |e| e.vote_ratio = -0.1, false),
+            ("vote ratio NaN", |e| e.vote_ratio = f32::NAN, false),
+        ];
+
+        for (name, change, valid) in cases {
+            let mut config = match_config();
+            change(&mut config);
+            assert_eq!(config.validate(5).is_ok(), valid, "{name}");
+        }
+    }
 }
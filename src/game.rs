@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use crate::data::MatchConfig;
+use crate::data::{CharacterTier, MatchConfig};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum ChatMessageType {
@@ -31,6 +31,124 @@ impl From<i32> for CtrlType {
     }
 }
 
+///How the stage changes when going back to the lobby, numbered like the C# enum
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "i32", into = "i32")]
+pub enum StageRotationMode {
+    None = 0,
+    Sequenced = 1,
+    Random = 2,
+}
+
+impl TryFrom<i32> for StageRotationMode {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(StageRotationMode::None),
+            1 => Ok(StageRotationMode::Sequenced),
+            2 => Ok(StageRotationMode::Random),
+            _ => Err(format!("Unknown stage rotation mode {value}")),
+        }
+    }
+}
+
+impl From<StageRotationMode> for i32 {
+    fn from(value: StageRotationMode) -> Self {
+        value as i32
+    }
+}
+
+///Which character tiers players may race with, numbered like the C# enum
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "i32", into = "i32")]
+pub enum AllowedTiers {
+    #[default]
+    All = 0,
+    NormalOnly = 1,
+    OddOnly = 2,
+    HyperspeedOnly = 3,
+    NoHyperspeed = 4,
+}
+
+impl AllowedTiers {
+    pub fn allows(self, tier: CharacterTier) -> bool {
+        match self {
+            AllowedTiers::All => true,
+            AllowedTiers::NormalOnly => tier == CharacterTier::Normal,
+            AllowedTiers::OddOnly => tier == CharacterTier::Odd,
+            AllowedTiers::HyperspeedOnly => tier == CharacterTier::Hyperspeed,
+            AllowedTiers::NoHyperspeed => tier != CharacterTier::Hyperspeed,
+        }
+    }
+
+    ///What we tell players about the allowed characters
+    pub fn text(self) -> &'static str {
+        match self {
+            AllowedTiers::All => "All characters are allowed.",
+            AllowedTiers::NormalOnly => "Only characters from the Normal tier are allowed.",
+            AllowedTiers::OddOnly => "Only characters from the Odd tier are allowed.",
+            AllowedTiers::HyperspeedOnly => "Only characters from the Hyperspeed tier are allowed.",
+            AllowedTiers::NoHyperspeed => "Any character NOT from the Hyperspeed tier is allowed.",
+        }
+    }
+}
+
+impl TryFrom<i32> for AllowedTiers {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(AllowedTiers::All),
+            1 => Ok(AllowedTiers::NormalOnly),
+            2 => Ok(AllowedTiers::OddOnly),
+            3 => Ok(AllowedTiers::HyperspeedOnly),
+            4 => Ok(AllowedTiers::NoHyperspeed),
+            _ => Err(format!("Unknown allowed tiers {value}")),
+        }
+    }
+}
+
+impl From<AllowedTiers> for i32 {
+    fn from(value: AllowedTiers) -> Self {
+        value as i32
+    }
+}
+
+///How the allowed tiers change when going back to the lobby, numbered like the C# enum
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "i32", into = "i32")]
+pub enum TierRotationMode {
+    #[default]
+    None = 0,
+    ///Normal, then odd, then hyperspeed
+    Cycle = 1,
+    ///Same chance for normal, odd and hyperspeed
+    Random = 2,
+    ///Mostly normal, sometimes odd, rarely hyperspeed
+    WeightedRandom = 3,
+}
+
+impl TryFrom<i32> for TierRotationMode {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(TierRotationMode::None),
+            1 => Ok(TierRotationMode::Cycle),
+            2 => Ok(TierRotationMode::Random),
+            3 => Ok(TierRotationMode::WeightedRandom),
+            _ => Err(format!("Unknown tier rotation mode {value}")),
+        }
+    }
+}
+
+impl From<TierRotationMode> for i32 {
+    fn from(value: TierRotationMode) -> Self {
+        value as i32
+    }
+}
+
 pub enum GameHeader {
     MatchMessage = 0,
     InitMessage = 1,
@@ -105,7 +223,7 @@ pub enum MessageTypes {
         ctrl_type: i32,
         time: f32,
     },
-    #[serde(rename_all = "PascalCase")]
+    #[serde(rename = "SettingsChangedMessage", rename_all = "PascalCase")]
     SettingsChanged { new_match_settings: MatchConfig },
     #[serde(rename_all = "PascalCase")]
     StartRaceMessage {},
@@ -170,8 +288,25 @@ impl MessageTypes {
             } => "RaceTimeoutMessage",
             MessageTypes::SettingsChanged {
                 new_match_settings: _,
-            } => "SettingsChanged",
+            } => "SettingsChangedMessage",
             MessageTypes::StartRaceMessage {} => "StartRaceMessage",
         }
     }
+
+    ///The client a message acts for, only that client is allowed to send it.
+    ///Not for ClientJoinedMessage, the client doesn't exist until it's handled.
+    pub fn acting_client(&self) -> Option<&str> {
+        match self {
+            MessageTypes::ChangedReadyMessage { client_guid, .. }
+            | MessageTypes::CharacterChangedMessage { client_guid, .. }
+            | MessageTypes::CheckpointPassedMessage { client_guid, .. }
+            | MessageTypes::ClientLeftMessage { client_guid }
+            | MessageTypes::DoneRacingMessage { client_guid, .. }
+            | MessageTypes::PlayerJoinedMessage { client_guid, .. }
+            | MessageTypes::PlayerLeftMessage { client_guid, .. }
+            | MessageTypes::RaceFinishedMessage { client_guid, .. }
+            | MessageTypes::RaceTimeoutMessage { client_guid, .. } => Some(client_guid),
+            _ => None,
+        }
+    }
 }
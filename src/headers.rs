@@ -160,19 +160,153 @@ impl TryFrom<u8> for Header {
     fn try_from(value: u8) -> std::result::Result<Header, HeaderError> {
         match value {
             1 => Ok(Header::UserUnreliable),
+            2 => Ok(Header::UserSequenced1),
+            3 => Ok(Header::UserSequenced2),
+            4 => Ok(Header::UserSequenced3),
+            5 => Ok(Header::UserSequenced4),
+            6 => Ok(Header::UserSequenced5),
+            7 => Ok(Header::UserSequenced6),
+            8 => Ok(Header::UserSequenced7),
+            9 => Ok(Header::UserSequenced8),
+            10 => Ok(Header::UserSequenced9),
+            11 => Ok(Header::UserSequenced10),
+            12 => Ok(Header::UserSequenced11),
+            13 => Ok(Header::UserSequenced12),
+            14 => Ok(Header::UserSequenced13),
+            15 => Ok(Header::UserSequenced14),
+            16 => Ok(Header::UserSequenced15),
+            17 => Ok(Header::UserSequenced16),
+            18 => Ok(Header::UserSequenced17),
+            19 => Ok(Header::UserSequenced18),
+            20 => Ok(Header::UserSequenced19),
+            21 => Ok(Header::UserSequenced20),
+            22 => Ok(Header::UserSequenced21),
+            23 => Ok(Header::UserSequenced22),
+            24 => Ok(Header::UserSequenced23),
+            25 => Ok(Header::UserSequenced24),
+            26 => Ok(Header::UserSequenced25),
+            27 => Ok(Header::UserSequenced26),
+            28 => Ok(Header::UserSequenced27),
+            29 => Ok(Header::UserSequenced28),
+            30 => Ok(Header::UserSequenced29),
+            31 => Ok(Header::UserSequenced30),
+            32 => Ok(Header::UserSequenced31),
+            33 => Ok(Header::UserSequenced32),
+            34 => Ok(Header::UserReliableUnordered),
+            35 => Ok(Header::UserReliableSequenced1),
+            36 => Ok(Header::UserReliableSequenced2),
+            37 => Ok(Header::UserReliableSequenced3),
+            38 => Ok(Header::UserReliableSequenced4),
+            39 => Ok(Header::UserReliableSequenced5),
+            40 => Ok(Header::UserReliableSequenced6),
+            41 => Ok(Header::UserReliableSequenced7),
+            42 => Ok(Header::UserReliableSequenced8),
+            43 => Ok(Header::UserReliableSequenced9),
+            44 => Ok(Header::UserReliableSequenced10),
+            45 => Ok(Header::UserReliableSequenced11),
+            46 => Ok(Header::UserReliableSequenced12),
+            47 => Ok(Header::UserReliableSequenced13),
+            48 => Ok(Header::UserReliableSequenced14),
+            49 => Ok(Header::UserReliableSequenced15),
+            50 => Ok(Header::UserReliableSequenced16),
+            51 => Ok(Header::UserReliableSequenced17),
+            52 => Ok(Header::UserReliableSequenced18),
+            53 => Ok(Header::UserReliableSequenced19),
+            54 => Ok(Header::UserReliableSequenced20),
+            55 => Ok(Header::UserReliableSequenced21),
+            56 => Ok(Header::UserReliableSequenced22),
+            57 => Ok(Header::UserReliableSequenced23),
+            58 => Ok(Header::UserReliableSequenced24),
+            59 => Ok(Header::UserReliableSequenced25),
+            60 => Ok(Header::UserReliableSequenced26),
+            61 => Ok(Header::UserReliableSequenced27),
+            62 => Ok(Header::UserReliableSequenced28),
+            63 => Ok(Header::UserReliableSequenced29),
+            64 => Ok(Header::UserReliableSequenced30),
+            65 => Ok(Header::UserReliableSequenced31),
+            66 => Ok(Header::UserReliableSequenced32),
             67 => Ok(Header::UserReliableOrdered1),
+            68 => Ok(Header::UserReliableOrdered2),
+            69 => Ok(Header::UserReliableOrdered3),
+            70 => Ok(Header::UserReliableOrdered4),
+            71 => Ok(Header::UserReliableOrdered5),
+            72 => Ok(Header::UserReliableOrdered6),
+            73 => Ok(Header::UserReliableOrdered7),
+            74 => Ok(Header::UserReliableOrdered8),
+            75 => Ok(Header::UserReliableOrdered9),
+            76 => Ok(Header::UserReliableOrdered10),
+            77 => Ok(Header::UserReliableOrdered11),
+            78 => Ok(Header::UserReliableOrdered12),
+            79 => Ok(Header::UserReliableOrdered13),
+            80 => Ok(Header::UserReliableOrdered14),
+            81 => Ok(Header::UserReliableOrdered15),
+            82 => Ok(Header::UserReliableOrdered16),
+            83 => Ok(Header::UserReliableOrdered17),
+            84 => Ok(Header::UserReliableOrdered18),
+            85 => Ok(Header::UserReliableOrdered19),
+            86 => Ok(Header::UserReliableOrdered20),
+            87 => Ok(Header::UserReliableOrdered21),
+            88 => Ok(Header::UserReliableOrdered22),
+            89 => Ok(Header::UserReliableOrdered23),
+            90 => Ok(Header::UserReliableOrdered24),
+            91 => Ok(Header::UserReliableOrdered25),
+            92 => Ok(Header::UserReliableOrdered26),
+            93 => Ok(Header::UserReliableOrdered27),
+            94 => Ok(Header::UserReliableOrdered28),
+            95 => Ok(Header::UserReliableOrdered29),
+            96 => Ok(Header::UserReliableOrdered30),
+            97 => Ok(Header::UserReliableOrdered31),
+            98 => Ok(Header::UserReliableOrdered32),
             99..=127 => Err(HeaderError::DoesNotExist),
             129 => Ok(Header::Ping),
-            //130 => Ok(Header::Pong),
+            130 => Ok(Header::Pong),
             131 => Ok(Header::Connect),
             133 => Ok(Header::ConnectionEstablished),
             134 => Ok(Header::Acknowledge),
-            141..=255 => Err(HeaderError::DoesNotExist),
+            135 => Ok(Header::Disconnect),
+            136 => Ok(Header::Discovery),
+            140 => Ok(Header::ExpandMTURequest),
+            141 => Ok(Header::ExpandMTUSuccess),
+            144..=255 => Err(HeaderError::DoesNotExist),
             _ => Err(HeaderError::NotEnabled),
         }
     }
 }
 
+///How Lidgren delivers the user messages sent on a channel
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Delivery {
+    ///Might not arrive, might arrive twice, in any order
+    Unreliable,
+    ///Might not arrive, messages older than the last one handled are dropped
+    Sequenced,
+    ///Always arrives once, in any order
+    ReliableUnordered,
+    ///Always arrives unless a newer message got there first
+    ReliableSequenced,
+    ///Always arrives once, in the order it was sent
+    ReliableOrdered,
+}
+
+impl Delivery {
+    ///The delivery of a message type, None if it isn't a user message
+    pub fn of(header: u8) -> Option<Delivery> {
+        match header {
+            1 => Some(Delivery::Unreliable),
+            2..=33 => Some(Delivery::Sequenced),
+            34 => Some(Delivery::ReliableUnordered),
+            35..=66 => Some(Delivery::ReliableSequenced),
+            67..=98 => Some(Delivery::ReliableOrdered),
+            _ => None,
+        }
+    }
+
+    ///Whether the other side acknowledges the messages and they get resent until it does
+    pub fn is_reliable(self) -> bool {
+        !matches!(self, Delivery::Unreliable | Delivery::Sequenced)
+    }
+}
+
 #[derive(Clone)]
 pub enum HeaderError {
     DoesNotExist,
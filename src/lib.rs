@@ -0,0 +1,88 @@
+pub mod bans;
+pub mod buffer;
+pub mod stream;
+
+pub mod data;
+
+pub mod channel;
+pub mod commands;
+pub mod connection;
+pub mod game;
+pub mod headers;
+pub mod server;
+pub mod server_list;
+pub mod version;
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::hash_map::RandomState,
+    error::Error,
+    fs::{self, File},
+    hash::{BuildHasher, Hasher},
+    io::BufReader,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+///Where the match settings are loaded from and saved to
+pub const MATCH_SETTINGS_FILE: &str = "match.json";
+
+pub const MOTD_FILE: &str = "motd.json";
+
+pub const BANS_FILE: &str = "bans.json";
+
+pub fn to_byte(num: usize) -> u8 {
+    (num & 0xFF).try_into().unwrap()
+}
+
+///A random number below `max`, std seeds every hasher with fresh random keys
+pub fn random(max: usize) -> usize {
+    let seed = RandomState::new().build_hasher().finish();
+    (seed % max.max(1) as u64) as usize
+}
+
+///The time of day in UTC, for putting in front of logs
+pub fn timestamp() -> String {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|e| e.as_secs())
+        .unwrap_or(0);
+
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+///Turn a C# JSON into a Rust JSON
+pub fn oxidize(mut json: String) -> String {
+    json = json.replace("SanicballCore.MatchMessages.", "");
+    json.replace(", SanicballCore", "")
+}
+
+pub fn load_file<T>(filename: &str) -> Result<T, Box<dyn Error>>
+where
+    T: DeserializeOwned,
+{
+    let file = File::open(filename)?;
+    let reader = BufReader::new(file);
+    let json = serde_json::from_reader(reader)?;
+
+    Ok(json)
+}
+
+///Write a JSON file without ever leaving it half written.
+///The JSON goes to a temporary file first, which then replaces the old one.
+pub fn save_file<T>(filename: &str, value: &T) -> Result<(), Box<dyn Error>>
+where
+    T: Serialize,
+{
+    let temporary = format!("{filename}.tmp");
+    let json = serde_json::to_string_pretty(value)?;
+
+    fs::write(&temporary, json)?;
+    fs::rename(&temporary, filename)?;
+
+    Ok(())
+}
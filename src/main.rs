@@ -1,51 +1,51 @@
-pub mod buffer;
-pub mod stream;
-
-pub mod data;
-
-pub mod commands;
-pub mod game;
-pub mod headers;
-pub mod server;
-pub mod version;
-
-use serde::de::DeserializeOwned;
-use server::Server;
-use std::{error::Error, fs::File, io::BufReader};
+use sanicball_server::{
+    bans::BanList,
+    commands::{CommandRegistry, Console, ConsoleOutput, Permission},
+    data::{MatchConfig, ServerConfig},
+    load_file,
+    server::Server,
+    server_list::ServerList,
+    BANS_FILE, MATCH_SETTINGS_FILE, MOTD_FILE,
+};
+use std::path::Path;
 
 //TODO Make this into an executable app
 //TODO Turn into CLI
 fn main() {
     //env::set_var("RUST_BACKTRACE", "full");
-    let server_config = load_file("config.json").unwrap();
-    let match_config = load_file("match.json").unwrap();
-    let motd = load_file("motd.json").unwrap();
-
-    let mut server = Server::new(server_config, match_config, motd);
-
-    loop {
+    let server_config: ServerConfig = load_file("config.json").unwrap();
+    if server_config.public {
+        if let Err(reason) = ServerList::check_urls(&server_config.servers) {
+            panic!("Invalid config.json: {reason}");
+        }
+    }
+    let match_config: MatchConfig = load_file(MATCH_SETTINGS_FILE).unwrap();
+    if let Err(reason) = match_config.validate(server_config.stage_count) {
+        panic!("Invalid {MATCH_SETTINGS_FILE}: {reason}");
+    }
+    let motd = load_file(MOTD_FILE).unwrap();
+    //Nobody was banned yet if there's no file
+    let bans = match Path::new(BANS_FILE).exists() {
+        true => load_file(BANS_FILE).unwrap(),
+        false => BanList::default(),
+    };
+
+    let mut server = Server::new(server_config, match_config, motd, bans);
+    let console = Console::new();
+    let commands = CommandRegistry::with_builtins();
+
+    while server.is_running() {
         server.update();
+        for line in console.lines() {
+            commands.run(
+                &mut server,
+                &line,
+                None,
+                Permission::Admin,
+                &mut ConsoleOutput,
+            );
+        }
+        commands.run_chat(&mut server);
         //sleep(Duration::from_secs(1))
     }
 }
-
-pub fn to_byte(num: usize) -> u8 {
-    (num & 0xFF).try_into().unwrap()
-}
-
-///Turn a C# JSON into a Rust JSON
-pub fn oxidize(mut json: String) -> String {
-    json = json.replace("SanicballCore.MatchMessages.", "");
-    json.replace(", SanicballCore", "")
-}
-
-fn load_file<T>(filename: &str) -> Result<T, Box<dyn Error>>
-where
-    T: DeserializeOwned,
-{
-    let file = File::open(filename)?;
-    let reader = BufReader::new(file);
-    let json = serde_json::from_reader(reader)?;
-
-    Ok(json)
-}
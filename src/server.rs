@@ -1,18 +1,55 @@
-use std::net::{SocketAddr, UdpSocket};
-use std::time::Duration;
-use std::usize;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
 use std::vec;
 
-use crate::data::{Clock, MatchConfig, Motd, ServerConfig, Stopwatch};
-use crate::game::{ChatMessageType, GameHeader, MessageTypes};
+use crate::bans::{BanList, BanTarget};
+use crate::connection::{Connection, ConnectionState};
+use crate::data::{
+    CharacterTier, ClientInfo, Clock, MatchConfig, Motd, ServerConfig, ServerInfo, Timer,
+    CHARACTER_TIERS,
+};
+use crate::game::{
+    AllowedTiers, ChatMessageType, GameHeader, MessageTypes, StageRotationMode, TierRotationMode,
+};
 use crate::oxidize;
+use crate::random;
+use crate::server_list::ServerList;
+use crate::timestamp;
+use crate::version::{APP_ID, IS_TESTING, VERSION_FLOAT};
 use crate::{
     buffer::Buffer,
     data::{Client, Player},
-    headers::Header,
-    stream::Stream,
+    headers::{Delivery, Header},
+    load_file, save_file,
+    stream::{DecodeError, ReadResult, Stream},
+    BANS_FILE, MATCH_SETTINGS_FILE, MOTD_FILE,
 };
 
+///Biggest datagram we can receive, Lidgren peers can expand their MTU past the usual 1500
+const MAX_DATAGRAM: usize = 8192;
+
+///MTU we try to get to after the handshake, the biggest that fits in an ethernet frame
+const EXPANDED_MTU: usize = 1472;
+
+///How long we wait for everyone to load the stage before starting the race anyway
+const STAGE_LOADING_TIMEOUT: Duration = Duration::from_secs(20);
+
+///How long chat messages count towards the rate limit
+const CHAT_RATE_WINDOW: Duration = Duration::from_secs(10);
+
+///How many wrong passwords an adress can try before it has to wait
+const LOGIN_ATTEMPTS: usize = 3;
+
+///How long wrong passwords count towards the login limit
+const LOGIN_LOCKOUT: Duration = Duration::from_secs(60);
+
+///Ogre, what everyone who mentions shrek turns into
+const SHREK_CHARACTER: i32 = 15;
+
+const INVALID_CLIENT_INFO: &str =
+    "Invalid client info! You are likely using a different game version than the server.";
+
 pub struct Server {
     app_id: &'static str,
 
@@ -21,11 +58,23 @@ pub struct Server {
     config: ServerConfig,
     match_settings: MatchConfig,
     motd: Motd,
+    bans: BanList,
 
     clock: Clock,
+    in_race: bool,
+    ///False once the server was shut down
+    running: bool,
+    server_list: ServerList,
 
+    connections: HashMap<SocketAddr, Connection>,
     clients: Vec<Client>,
     players: Vec<Player>,
+    ///Chat messages starting with / that wait to be run as commands, with who sent them
+    chat_commands: Vec<(SocketAddr, String)>,
+    ///GUIDs of the clients whose chat messages are blocked
+    muted: Vec<Vec<u8>>,
+    ///When every adress last tried a wrong admin password, so it can't be guessed
+    login_failures: HashMap<IpAddr, Vec<Instant>>,
 
     buffer: Buffer,
     stream: Stream,
@@ -41,7 +90,29 @@ impl Server {
 
         //Return a small vector that send
         let message = self.buffer.message();
-        self.listener.send_to(&message, addr).unwrap();
+        self.send_message(message, addr);
+    }
+
+    ///Send a message with the next datagram, or right away if the adress has no connection to pack it in
+    fn send_message(&mut self, message: Vec<u8>, addr: SocketAddr) {
+        match self.connections.get_mut(&addr) {
+            Some(connection) => connection.send(message),
+            None => {
+                self.listener.send_to(&message, addr).unwrap();
+            }
+        }
+    }
+
+    ///Send a reliable message, it will be resent until the adress acknowledges it
+    fn send_reliable(&mut self, buffer: &Buffer, addr: SocketAddr) {
+        let Some(connection) = self.connections.get_mut(&addr) else {
+            return;
+        };
+        if !connection.is_active() {
+            return;
+        }
+
+        connection.send_user(buffer);
     }
 
     //Send the buffer to every specified adress
@@ -49,13 +120,12 @@ impl Server {
         //Add the header
         self.buffer.write_header(header);
 
-        //Return a small vector that send
-        for client in self.clients.iter_mut() {
-            self.buffer.seq(client.counter);
-            client.counter += 1;
-            let message = self.buffer.message();
-            self.listener.send_to(&message, client.connection).unwrap();
+        let buffer = std::mem::take(&mut self.buffer);
+        let addresses: Vec<SocketAddr> = self.clients.iter().map(|e| e.connection).collect();
+        for addr in addresses {
+            self.send_reliable(&buffer, addr);
         }
+        self.buffer = buffer;
     }
 
     fn send_new(&mut self, header: MessageTypes) {
@@ -66,16 +136,14 @@ impl Server {
         buffer.write_json(header);
         buffer.write_header(Header::UserReliableOrdered1);
 
-        for client in self.clients.iter_mut() {
-            buffer.seq(client.counter);
-            client.counter += 1;
-            let message = buffer.message();
-            self.listener.send_to(&message, client.connection).unwrap();
+        let addresses: Vec<SocketAddr> = self.clients.iter().map(|e| e.connection).collect();
+        for addr in addresses {
+            self.send_reliable(&buffer, addr);
         }
     }
 
     ///Create a new message and send it to an address' chat
-    fn chat_to(&mut self, message: &str, socket: SocketAddr) {
+    pub fn chat_to(&mut self, message: &str, socket: SocketAddr) {
         let mut buffer = Buffer::default();
         let email = MessageTypes::ChatMessage {
             from: "Server".to_owned(),
@@ -89,11 +157,11 @@ impl Server {
         buffer.write_json(email);
         buffer.write_header(Header::UserReliableOrdered1);
 
-        self.listener.send_to(&buffer.message(), socket).unwrap();
+        self.send_reliable(&buffer, socket);
     }
 
     ///Create a new message and send it to every chat
-    fn chat_all(&mut self, message: &str) {
+    pub fn chat_all(&mut self, message: &str) {
         let mut buffer = Buffer::default();
         let email = MessageTypes::ChatMessage {
             from: "Server".to_owned(),
@@ -107,24 +175,482 @@ impl Server {
         buffer.write_json(email);
         buffer.write_header(Header::UserReliableOrdered1);
 
-        for client in self.clients.iter() {
-            self.listener
-                .send_to(&buffer.message(), client.connection)
-                .unwrap();
+        let addresses: Vec<SocketAddr> = self.clients.iter().map(|e| e.connection).collect();
+        for addr in addresses {
+            self.send_reliable(&buffer, addr);
         }
     }
 
     fn ack(&mut self) {
+        if let Some(connection) = self.connections.get_mut(&self.stream.origin) {
+            connection.ack(self.stream.header_byte, self.stream.sequence);
+        }
+    }
+
+    ///Mark the acknowledged messages as delivered so they stop being resent
+    fn read_acks(&mut self) -> ReadResult<()> {
+        let Some(connection) = self.connections.get_mut(&self.stream.origin) else {
+            return Ok(());
+        };
+
+        while self.stream.remaining() >= 3 {
+            let header = self.stream.read_byte()?;
+            let low: u16 = self.stream.read_byte()?.into();
+            let high: u16 = self.stream.read_byte()?.into();
+
+            connection.acknowledge(header, low | (high << 8));
+        }
+
+        Ok(())
+    }
+
+    ///Send again everything that wasn't acknowledged in time
+    fn resend(&mut self) {
+        for connection in self.connections.values_mut() {
+            connection.resend();
+        }
+    }
+
+    ///Send everything the connections have waiting, packed together so there's fewer datagrams
+    fn flush(&mut self) {
+        for connection in self.connections.values_mut() {
+            for datagram in connection.flush() {
+                self.listener.send_to(&datagram, connection.origin).unwrap();
+            }
+        }
+    }
+
+    ///Ping everyone who's due, drop whoever went silent and clean up closed connections
+    fn heartbeats(&mut self) {
+        let mut timed_out = vec![];
+
+        for connection in self.connections.values_mut() {
+            if connection.timed_out() {
+                timed_out.push(connection.origin);
+            }
+
+            if let Some(ping_number) = connection.ping() {
+                let mut buffer = Buffer::default();
+                buffer.write_byte(ping_number);
+                buffer.write_header(Header::Ping);
+                connection.send(buffer.message());
+            }
+
+            connection.linger();
+        }
+
+        for addr in timed_out {
+            println!("{addr} timed out");
+            self.disconnect(addr, "Connection timed out");
+        }
+
+        self.connections
+            .retain(|_, e| e.state != ConnectionState::Gone);
+    }
+
+    ///Tell an adress we're closing the connection and forget about its client
+    fn disconnect(&mut self, addr: SocketAddr, reason: &str) {
+        let Some(connection) = self.connections.get_mut(&addr) else {
+            return;
+        };
+        if !connection.is_active() {
+            return;
+        }
+        connection.disconnect();
+
+        self.send_disconnect(addr, reason);
+        self.remove_client(addr, reason);
+    }
+
+    ///Send a Disconnect with a reason the game shows to the player
+    fn send_disconnect(&mut self, addr: SocketAddr, reason: &str) {
         let mut buffer = Buffer::default();
+        buffer.write_string(reason);
+        buffer.write_header(Header::Disconnect);
+        self.send_message(buffer.message(), addr);
+    }
 
-        buffer.write_byte(self.stream.header_byte);
-        buffer.write_byte((self.stream.sequence & 0xFF).try_into().unwrap());
-        buffer.write_byte((self.stream.sequence >> 8).try_into().unwrap());
-        buffer.write_header(Header::Acknowledge);
+    ///Read the rest of a Connect message and decide if the client may join.
+    ///Returns why the client was denied, or None if it's approved.
+    fn deny_reason(&mut self) -> ReadResult<Option<String>> {
+        //GUIDs are only sent once the client joins, adresses can be turned away right away
+        if let Some(ban) = self.bans.find_ip(self.stream.origin.ip()) {
+            return Ok(Some(ban.message()));
+        }
 
-        self.listener
-            .send_to(&buffer.message(), self.stream.origin)
-            .unwrap();
+        let app_id = self.stream.read_string()?;
+        if app_id != self.app_id {
+            return Ok(Some(format!("Wrong application identifier {app_id:?}")));
+        }
+
+        //Lidgren's unique identifier for the peer and the time on its clock
+        let _unique_id = self.stream.read_i64()?;
+        let _remote_time = self.stream.read_f32()?;
+
+        //The hail message, the game puts its ClientInfo as JSON in there
+        if self.stream.remaining() == 0 {
+            return Ok(Some(INVALID_CLIENT_INFO.to_owned()));
+        }
+        let hail = self.stream.read_string()?;
+        let info: ClientInfo = match serde_json::from_str(&hail) {
+            Ok(info) => info,
+            Err(error) => {
+                println!("Error reading client connection approval: {error}. Client rejected.");
+                return Ok(Some(INVALID_CLIENT_INFO.to_owned()));
+            }
+        };
+
+        if info.version != VERSION_FLOAT || info.is_testing != IS_TESTING {
+            return Ok(Some("Wrong game version.".to_owned()));
+        }
+
+        let origin = self.stream.origin;
+        let connected = self
+            .connections
+            .values()
+            .filter(|e| e.is_active() && e.origin != origin)
+            .count();
+        if connected >= usize::from(self.config.max_players) {
+            return Ok(Some("Server is full.".to_owned()));
+        }
+
+        Ok(None)
+    }
+
+    ///Remove a client and all of its players, then let everyone else know they left
+    fn remove_client(&mut self, addr: SocketAddr, reason: &str) {
+        let Some(index) = self.clients.iter().position(|e| e.connection == addr) else {
+            println!("Unknown client disconnected (Client was most likely not done connecting)");
+            return;
+        };
+        let client = self.clients.remove(index);
+        let guid = vec_to_guid(&client.guid);
+
+        let mut left = vec![];
+        self.players.retain(|e| {
+            if e.guid == client.guid {
+                left.push(e.ctrl_type);
+            }
+            e.guid != client.guid
+        });
+
+        for ctrl_type in left {
+            self.send_new(MessageTypes::PlayerLeftMessage {
+                client_guid: guid.clone(),
+                ctrl_type,
+            });
+        }
+        if self.players.is_empty() && self.in_race {
+            println!("No players left in race!");
+            self.return_to_lobby();
+        }
+        self.check_auto_start_players();
+        self.check_ready();
+        self.send_new(MessageTypes::ClientLeftMessage { client_guid: guid });
+        self.chat_all(&format!("{} has left the match ({reason})", client.name));
+
+        //Don't keep the others waiting on a client that's gone
+        self.check_loaded();
+    }
+
+    ///Kick a client off the server
+    pub fn kick(&mut self, addr: SocketAddr, reason: &str) {
+        println!("Kicking {addr} ({reason})");
+        self.disconnect(addr, reason);
+    }
+
+    ///Say goodbye to every connection before the server closes
+    pub fn shutdown(&mut self) {
+        self.save_match_settings();
+
+        let addresses: Vec<SocketAddr> = self.connections.keys().copied().collect();
+        for addr in addresses {
+            self.disconnect(addr, "Server was closed.");
+        }
+
+        //There's no next update to send the goodbyes
+        self.flush();
+        self.running = false;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn in_race(&self) -> bool {
+        self.in_race
+    }
+
+    pub fn clients(&self) -> &[Client] {
+        &self.clients
+    }
+
+    pub fn match_settings(&self) -> &MatchConfig {
+        &self.match_settings
+    }
+
+    pub fn motd(&self) -> &str {
+        &self.motd.text
+    }
+
+    ///The commands sent over chat since the last call
+    pub fn take_chat_commands(&mut self) -> Vec<(SocketAddr, String)> {
+        std::mem::take(&mut self.chat_commands)
+    }
+
+    pub fn is_admin(&self, addr: SocketAddr) -> bool {
+        self.clients
+            .iter()
+            .any(|e| e.connection == addr && e.is_admin)
+    }
+
+    ///Make a client an admin if it knows the admin password
+    pub fn login(&mut self, addr: SocketAddr, password: &str) -> Result<(), String> {
+        if self.config.admin_password.is_empty() {
+            return Err("Logging in is disabled on this server".to_owned());
+        }
+        let Some(client) = self.clients.iter_mut().find(|e| e.connection == addr) else {
+            return Err("Only clients in the match can log in".to_owned());
+        };
+
+        //Reconnecting doesn't help, the failures are counted for the whole IP
+        let failures = self.login_failures.entry(addr.ip()).or_default();
+        failures.retain(|e| e.elapsed() < LOGIN_LOCKOUT);
+        if failures.len() >= LOGIN_ATTEMPTS {
+            println!("{} tried to log in again too soon", client.name);
+            return Err("Too many wrong passwords, try again later".to_owned());
+        }
+        if password != self.config.admin_password {
+            println!("{} tried to log in with the wrong password", client.name);
+            failures.push(Instant::now());
+            return Err("Wrong password".to_owned());
+        }
+
+        println!("{} logged in as admin", client.name);
+        client.is_admin = true;
+        Ok(())
+    }
+
+    ///Block or allow a client's chat messages, they stay muted if they reconnect
+    pub fn set_muted(&mut self, addr: SocketAddr, muted: bool) -> Result<(), String> {
+        let Some(client) = self.clients.iter().find(|e| e.connection == addr) else {
+            return Err("Only clients in the match can be muted".to_owned());
+        };
+        let guid = client.guid.clone();
+
+        self.muted.retain(|e| *e != guid);
+        if muted {
+            self.muted.push(guid);
+        }
+        Ok(())
+    }
+
+    ///Vote to go back to the lobby for a client, like the game's own button
+    pub fn vote_lobby_from(&mut self, addr: SocketAddr) -> Result<(), String> {
+        if !self.in_race {
+            return Err("You can only vote to return to the lobby during a race".to_owned());
+        }
+        let Some(index) = self.clients.iter().position(|e| e.connection == addr) else {
+            return Err("Only clients in the match can vote".to_owned());
+        };
+        if self.clients[index].wants_lobby {
+            return Err("You already voted to return to the lobby".to_owned());
+        }
+
+        self.vote_lobby(index);
+        Ok(())
+    }
+
+    ///Change the match settings if they're still valid afterwards, then send and save them
+    pub fn change_settings<F>(&mut self, change: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut MatchConfig),
+    {
+        let mut match_settings = self.match_settings.clone();
+        change(&mut match_settings);
+        match_settings.validate(self.config.stage_count)?;
+
+        self.match_settings = match_settings;
+        self.correct_player_tiers();
+        self.send_settings();
+        self.save_match_settings();
+        Ok(())
+    }
+
+    ///Load the message of the day from its file again, new clients get the new one
+    pub fn reload_motd(&mut self) -> Result<(), String> {
+        self.motd = load_file(MOTD_FILE).map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    ///If the current message is a fragment, store it and swap in the whole message once it's complete.
+    ///Returns false while fragments are still missing.
+    fn reassemble(&mut self, addr: SocketAddr) -> ReadResult<bool> {
+        if !self.stream.fragment {
+            return Ok(true);
+        }
+        let Some(connection) = self.connections.get_mut(&addr) else {
+            return Ok(false);
+        };
+
+        match connection.reassemble(self.stream.clone())? {
+            Some(whole) => {
+                self.stream = whole;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    ///Ask the other side if it can take bigger datagrams so we fragment less.
+    ///Sent on its own, the point is to see if a datagram this big gets through.
+    fn expand_mtu(&mut self, addr: SocketAddr) {
+        let mut buffer = Buffer::default();
+        for _ in buffer.payload.len()..EXPANDED_MTU {
+            buffer.write_byte(0);
+        }
+        buffer.write_header(Header::ExpandMTURequest);
+
+        let message = buffer.message();
+        if let Some(connection) = self.connections.get_mut(&addr) {
+            connection.probe_mtu(message.len());
+        }
+        self.listener.send_to(&message, addr).unwrap();
+    }
+
+    ///Everything a client needs to know about the match when it connects
+    fn write_init_message(&mut self) {
+        self.buffer.write_game_header(GameHeader::InitMessage);
+
+        self.buffer.write_clients(&self.clients);
+        self.buffer.write_players(&self.players);
+        self.buffer.write_settings(&self.match_settings);
+
+        //In race
+        self.buffer.write_bool(self.in_race);
+        //Cur auto start time
+        let time_left = self.auto_start_time_left();
+        self.buffer.write_f32(&time_left);
+
+        self.buffer.write_header(Header::UserReliableOrdered1);
+    }
+
+    ///Round trip time of an adress, for showing to the operator
+    fn latency(&self, addr: SocketAddr) -> String {
+        match self.connections.get(&addr).and_then(|e| e.rtt) {
+            Some(rtt) => format!("{} ms", rtt.as_millis()),
+            None => "? ms".to_owned(),
+        }
+    }
+
+    ///Keep someone off the server, for `duration` or forever if it's None.
+    ///Anyone the ban applies to is disconnected right away.
+    pub fn ban(
+        &mut self,
+        target: BanTarget,
+        reason: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), String> {
+        let log = format!("Banning {target} ({reason})");
+        self.bans.add(target, reason, duration)?;
+        println!("{log}");
+        self.save_bans();
+
+        let mut banned = vec![];
+        for client in self.clients.iter() {
+            if let Some(ban) = self.bans.find_guid(&vec_to_guid(&client.guid)) {
+                banned.push((client.connection, ban.message()));
+            }
+        }
+        for addr in self.connections.keys() {
+            if let Some(ban) = self.bans.find_ip(addr.ip()) {
+                banned.push((*addr, ban.message()));
+            }
+        }
+
+        for (addr, message) in banned {
+            self.disconnect(addr, &message);
+        }
+        Ok(())
+    }
+
+    ///Ban a client by its GUID
+    pub fn ban_client(
+        &mut self,
+        addr: SocketAddr,
+        reason: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), String> {
+        let Some(client) = self.clients.iter().find(|e| e.connection == addr) else {
+            return Err("Only clients in the match can be banned by name".to_owned());
+        };
+
+        let target = BanTarget::Guid(vec_to_guid(&client.guid));
+        self.ban(target, reason, duration)
+    }
+
+    pub fn unban(&mut self, target: &BanTarget) -> Result<(), String> {
+        if !self.bans.remove(target) {
+            return Err(format!("{target} is not banned"));
+        }
+
+        println!("Unbanned {target}");
+        self.save_bans();
+        Ok(())
+    }
+
+    ///Every ban that isn't over yet, one line each
+    pub fn ban_list(&mut self) -> Vec<String> {
+        self.bans.prune();
+
+        let mut lines = vec![format!("{} ban(s)", self.bans.bans().len())];
+        lines.extend(self.bans.bans().iter().map(|e| e.to_string()));
+        lines
+    }
+
+    ///Write the bans back to their file, without the ones that are over
+    fn save_bans(&mut self) {
+        self.bans.prune();
+        if let Err(e) = save_file(BANS_FILE, &self.bans) {
+            println!("Could not save bans: {e}");
+        }
+    }
+
+    ///Every connected client with its latency, one line each
+    pub fn client_list(&self) -> Vec<String> {
+        let mut lines = vec![format!("{} connected client(s)", self.clients.len())];
+        for client in self.clients.iter() {
+            let connection = self.connections.get(&client.connection);
+            let offset = match connection.and_then(|e| e.clock_offset) {
+                Some(offset) => format!("{offset:+.3} s"),
+                None => "? s".to_owned(),
+            };
+            lines.push(format!(
+                "{} ({}) ping {}, clock offset {offset}",
+                client.name,
+                client.connection,
+                self.latency(client.connection),
+            ));
+        }
+        lines
+    }
+
+    ///Every player in the match with the latency of the client it plays on, one line each
+    pub fn player_list(&self) -> Vec<String> {
+        let mut lines = vec![format!("{} player(s) in match", self.players.len())];
+        for player in self.players.iter() {
+            let Some(client) = self.clients.iter().find(|e| e.guid == player.guid) else {
+                continue;
+            };
+            lines.push(format!(
+                "{} (controls {}, character {}) ping {}",
+                client.name,
+                player.ctrl_type,
+                player.char_id,
+                self.latency(client.connection),
+            ));
+        }
+        lines
     }
 
     fn sending_client(&mut self) -> Option<usize> {
@@ -133,6 +659,12 @@ impl Server {
             .position(|e| e.connection == self.stream.origin)
     }
 
+    ///Whether the message came from the client with this GUID
+    fn sent_by(&mut self, guid: &[u8]) -> bool {
+        self.sending_client()
+            .is_some_and(|index| self.clients[index].guid == guid)
+    }
+
     fn current_client(&mut self, guid: Vec<u8>) -> Option<usize> {
         self.clients.iter().position(|e| e.guid == guid)
     }
@@ -143,144 +675,310 @@ impl Server {
             .position(|e| e.guid == guid && &e.ctrl_type == control)
     }
 
-    fn verify_player(&mut self, _player: Player) -> bool {
-        true
-    }
-
     // ? Start of Logic handling
     // ? If you read this from top to bottom you should get a pretty good grasp of what's going on
 
     ///Start a new Server
-    pub fn new(config: ServerConfig, matches: MatchConfig, motd: Motd) -> Server {
+    pub fn new(config: ServerConfig, matches: MatchConfig, motd: Motd, bans: BanList) -> Server {
         println!("Hello there welcome your stay");
-        println!("Starting server on ip: [129.0.0.1], port: [7878]");
 
-        Server {
-            app_id: "Sanicball",
-            listener: UdpSocket::bind("0.0.0.0:7878").unwrap(),
+        //The server lists are told this port, so it has to be the one we listen on
+        let port: u16 = config.port.try_into().unwrap();
+        let listener = UdpSocket::bind(("0.0.0.0", port)).unwrap();
+        println!("Starting server on {}", listener.local_addr().unwrap());
+        //Don't wait forever for a packet, unacknowledged messages need to be resent
+        listener
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        let mut server = Server {
+            app_id: APP_ID,
+            listener,
             config,
             match_settings: matches,
             motd,
+            bans,
             clock: Clock::default(),
+            in_race: false,
+            running: true,
+            server_list: ServerList::default(),
+            connections: HashMap::new(),
             clients: vec![],
             players: vec![],
+            chat_commands: vec![],
+            muted: vec![],
+            login_failures: HashMap::new(),
             buffer: Buffer::default(),
             stream: Stream::default(),
+        };
+
+        //Every time we send or compare with a peer's clock counts from here
+        server.clock.start_time.start();
+        if server.config.public {
+            server.add_to_server_lists();
         }
+
+        server
+    }
+
+    ///Tell every server list in the config that we're online
+    fn add_to_server_lists(&mut self) {
+        self.server_list
+            .add(&self.config.servers, &self.config.ip, self.config.port);
     }
 
     ///Update the Server's incoming requests
     pub fn update(&mut self) {
         self.timers();
-        let mut buffer = [0; 1500];
+        self.heartbeats();
+        self.resend();
+        let mut buffer = [0; MAX_DATAGRAM];
         let raw = self.listener.recv_from(&mut buffer);
 
         if let Ok((size, addr)) = raw {
-            self.stream = Stream::new(&buffer, size, addr);
-            self.buffer = Buffer::default();
+            //Lidgren packs as many messages in a datagram as it can
+            for stream in Stream::parse(&buffer[..size], addr) {
+                self.stream = match stream {
+                    Ok(stream) => stream,
+                    Err(error) => {
+                        println!("Dropping the rest of a packet from {addr} ({error})");
+                        break;
+                    }
+                };
+                self.buffer = Buffer::default();
+
+                //A broken message is only a problem for whoever sent it, not for the server
+                match self.handle(addr) {
+                    Ok(header) => self.respond(header, addr),
+                    Err(error) => println!("Dropping {} from {addr} ({error})", self.stream),
+                }
+            }
+        }
+
+        self.flush();
+    }
+
+    ///React to the message that just came in, returns how to send whatever it wrote in the buffer
+    fn handle(&mut self, addr: SocketAddr) -> ReadResult<Header> {
+        let active = match self.connections.get_mut(&addr) {
+            Some(connection) if connection.is_active() => {
+                connection.heard();
+                true
+            }
+            _ => false,
+        };
+
+        let header: Header = match self.stream.header {
+            //Only new connections and discovery are allowed to talk to us without a handshake
+            Ok(header) if !active && header != Header::Connect && header != Header::Discovery => {
+                println!("Ignoring {} from unconnected {addr}", self.stream);
+                Header::Unconnected
+            }
+            Ok(header) => match header {
+                Header::Acknowledge => {
+                    self.read_acks()?;
+                    Header::Unconnected
+                }
+                //* Correct as far as I can tell
+                Header::Ping => {
+                    let ping_number = self.stream.read_byte()?;
+
+                    self.buffer.write_byte(ping_number);
+                    self.buffer.write_time(&mut self.clock);
+
+                    //Add the header
+                    self.buffer.write_header(Header::Pong);
+                    let message = self.buffer.message();
+                    self.send_message(message, addr);
 
-            let header: Header = match self.stream.header {
-                Ok(header) => match header {
-                    // ! This is not correct
-                    Header::Acknowledge => Header::Unconnected,
-                    //* Correct as far as I can tell
-                    Header::Ping => {
-                        let ping_number = self.stream.read_byte();
+                    Header::Unconnected
+                }
+                Header::Pong => {
+                    let ping_number = self.stream.read_byte()?;
+                    let remote_time = self.stream.read_f32()?;
 
-                        self.buffer.write_byte(ping_number);
-                        self.buffer.write_time(&mut self.clock);
+                    let local_time = self.clock.now();
+                    if let Some(connection) = self.connections.get_mut(&addr) {
+                        connection.pong(ping_number, remote_time, local_time);
+                    }
 
-                        //Add the header
-                        self.buffer.write_header(Header::Pong);
-                        let message = self.buffer.message();
-                        self.listener.send_to(&message, self.stream.origin).unwrap();
+                    Header::Unconnected
+                }
+                //* Correct as far as I can tell
+                //Our ConnectResponse got lost, answer again without starting over
+                Header::Connect
+                    if self
+                        .connections
+                        .get(&addr)
+                        .is_some_and(|e| e.state == ConnectionState::Connected) =>
+                {
+                    self.buffer.write_string(self.app_id);
+
+                    Header::ConnectResponse
+                }
+                Header::Connect => match self.deny_reason()? {
+                    Some(reason) => {
+                        println!("Denied connection from {addr} ({reason})");
+                        self.send_disconnect(addr, &reason);
 
                         Header::Unconnected
                     }
-                    //* Correct as far as I can tell
-                    Header::Connect => {
+                    None => {
+                        //Whatever was left of an older connection from this adress is gone now
+                        if self.clients.iter().any(|e| e.connection == addr) {
+                            self.remove_client(addr, "Reconnected");
+                        }
+                        self.connections.insert(addr, Connection::new(addr));
+
                         //Initialize App ID on connect
-                        let _app_id = self.stream.read_string();
                         self.buffer.write_string(self.app_id);
 
-                        //TODO Figure out what this does
-                        // Don't ask because I don't know
-                        self.stream.read_f32();
-                        self.stream.read_f32();
-                        self.stream.read_f32();
-
-                        //TODO Check version is valid
-
                         Header::ConnectResponse
                     }
-                    //* Correct as far as I can tell
-                    Header::ConnectionEstablished => {
-                        //TODO Figure out what this does
-                        self.stream.read_f32();
-
-                        self.buffer.write_game_header(GameHeader::InitMessage);
+                },
+                //* Correct as far as I can tell
+                Header::ConnectionEstablished => {
+                    //TODO Figure out what this does
+                    self.stream.read_f32()?;
 
-                        self.buffer.write_clients(&self.clients);
-                        self.buffer.write_players(&self.players);
-                        self.buffer.write_settings(&self.match_settings);
+                    if let Some(connection) = self.connections.get_mut(&addr) {
+                        connection.establish();
+                    }
 
-                        //In race
-                        self.buffer.write_bool(false);
-                        //Cur auto start time
-                        self.buffer.write_i32(&67);
+                    self.write_init_message();
+                    let buffer = std::mem::take(&mut self.buffer);
+                    self.send_reliable(&buffer, addr);
+                    self.expand_mtu(addr);
 
-                        self.send_to(Header::UserReliableOrdered1, addr);
+                    Header::Unconnected
+                }
+                header if Delivery::of(header as u8).is_some() => {
+                    self.receive_user(addr);
+                    Header::Unconnected
+                }
+                Header::ExpandMTURequest => {
+                    //Tell the other side how big its datagram was, it got through so it can use it
+                    let size: i32 = self.stream.data.len().try_into().unwrap();
+                    self.buffer.write_i32(&size);
+                    self.buffer.write_header(Header::ExpandMTUSuccess);
+                    self.send_message(self.buffer.message(), addr);
 
-                        Header::Unconnected
+                    Header::Unconnected
+                }
+                Header::ExpandMTUSuccess => {
+                    let size: usize = self.stream.read_i32()?.try_into().unwrap_or(0);
+                    if let Some(connection) = self.connections.get_mut(&addr) {
+                        if connection.expand_mtu(size) {
+                            println!("MTU for {addr} is now {}", connection.mtu);
+                        }
                     }
-                    Header::UserReliableOrdered1 | Header::UserUnreliable => self.relay_data(),
-                    _ => panic!(
-                        "Tried to respond to a header that is not implamented {} (disable it)",
-                        self.stream
-                    ),
-                },
-                Err(_) => {
-                    println!("Ignoring {}", self.stream);
+
                     Header::Unconnected
                 }
-            };
+                Header::Discovery => {
+                    let info = ServerInfo::new(&self.config, self.clients.len(), self.in_race);
+                    self.buffer
+                        .write_string(&serde_json::to_string(&info).unwrap());
+
+                    println!("Sent discovery response to {addr}");
+                    Header::DiscoveryResponse
+                }
+                Header::Disconnect => {
+                    let reason = self.stream.read_string()?;
+                    println!("{addr} disconnected ({reason})");
+
+                    if let Some(connection) = self.connections.get_mut(&addr) {
+                        connection.disconnect();
+                    }
+                    self.remove_client(addr, &reason);
 
-            match header {
-                Header::Unconnected => {}
-                Header::ConnectResponse => self.send_to(header, addr),
-                _ => self.send_all(header),
+                    Header::Unconnected
+                }
+                //Enabled in headers.rs but not handled here yet
+                _ => {
+                    println!("Ignoring {} from {addr}, it's not handled", self.stream);
+                    Header::Unconnected
+                }
+            },
+            Err(_) => {
+                println!("Ignoring {}", self.stream);
+                Header::Unconnected
             }
-        }
+        };
+
+        Ok(header)
     }
 
-    ///Relay and react to a change in the game's state
-    fn relay_data(&mut self) -> Header {
-        self.ack();
+    ///Run a user message through its channel and relay whatever the channel lets through
+    fn receive_user(&mut self, addr: SocketAddr) {
+        if Delivery::of(self.stream.header_byte).is_some_and(Delivery::is_reliable) {
+            self.ack();
+        }
 
-        match self.stream.read_game_header() {
-            GameHeader::MatchMessage => {
-                let _time = self.stream.read_f32();
-                let json = self.stream.read_string();
+        let stream = self.stream.clone();
+        let ready = match self.connections.get_mut(&addr) {
+            Some(connection) => connection.receive(stream),
+            None => vec![],
+        };
 
-                self.buffer.write_game_header(GameHeader::MatchMessage);
-                self.buffer.write_time(&mut self.clock);
-                self.buffer.write_string(&json);
+        for stream in ready {
+            self.stream = stream;
+            self.buffer = Buffer::default();
 
-                let json = oxidize(json);
-                self.update_server_state(&json);
+            let relayed = match self.reassemble(addr) {
+                Ok(true) => self.relay_data(),
+                Ok(false) => Ok(Header::Unconnected),
+                Err(error) => Err(error),
+            };
+            match relayed {
+                Ok(header) => self.respond(header, addr),
+                Err(error) => println!("Dropping {} from {addr} ({error})", self.stream),
+            }
+        }
+    }
 
-                match self.sending_client() {
-                    Some(index) => {
-                        self.clients[index].counter = usize::from(self.stream.sequence) + 1
-                    }
-                    None => println!("Client is joining, sequence ignored"),
-                }
+    ///Send whatever was written into the buffer while handling the last message
+    fn respond(&mut self, header: Header, addr: SocketAddr) {
+        match header {
+            Header::Unconnected => {}
+            Header::ConnectResponse | Header::DiscoveryResponse => self.send_to(header, addr),
+            _ => self.send_all(header),
+        }
+    }
+
+    ///Relay and react to a change in the game's state
+    fn relay_data(&mut self) -> ReadResult<Header> {
+        match self.stream.read_game_header()? {
+            GameHeader::MatchMessage => {
+                let _time = self.stream.read_f32()?;
+                let json = self.stream.read_string()?;
+
+                self.buffer.write_game_header(GameHeader::MatchMessage);
+                self.buffer.write_time(&mut self.clock);
+                self.buffer.write_string(&json);
 
-                Header::UserReliableOrdered1
+                let json = oxidize(json);
+                match self.update_server_state(&json)? {
+                    true => Ok(Header::UserReliableOrdered1),
+                    false => Ok(Header::Unconnected),
+                }
             }
-            GameHeader::InitMessage => panic!(), // The game itself never sends this
+            // The game itself never sends this
+            GameHeader::InitMessage => Err(DecodeError::UnknownGameHeader(
+                GameHeader::InitMessage as u8,
+            )),
             GameHeader::PlayerMovementMessage => {
-                let _time = self.stream.read_f32();
+                let _time = self.stream.read_f32()?;
+
+                //Clients can only move their own players
+                let guid = self.stream.read_guid()?;
+                if !self.sent_by(&guid) || !self.players.iter().any(|e| e.guid == guid) {
+                    println!(
+                        "{} sent movement for a player that isn't theirs",
+                        self.stream.origin
+                    );
+                    return Ok(Header::Unconnected);
+                }
 
                 self.buffer
                     .write_game_header(GameHeader::PlayerMovementMessage);
@@ -294,80 +992,163 @@ impl Server {
                 //Add the header
                 self.buffer.write_header(Header::UserUnreliable);
 
-                //Return a small vector that send
-                let guid = match self.sending_client() {
-                    Some(index) => self.clients[index].guid.clone(),
-                    None => vec![],
-                };
-
-                for client in self.clients.iter_mut() {
-                    if client.guid == guid {
-                        continue;
-                    }
+                //Everyone but the sender gets it
+                let addresses: Vec<SocketAddr> = self
+                    .clients
+                    .iter()
+                    .filter(|e| e.guid != guid)
+                    .map(|e| e.connection)
+                    .collect();
+                for addr in addresses {
                     let message = self.buffer.message();
-                    self.listener.send_to(&message, client.connection).unwrap();
+                    self.send_message(message, addr);
                 }
 
-                Header::Unconnected
+                Ok(Header::Unconnected)
             }
         }
     }
 
     ///I don't think I need to explain why this isn't inlined
-    fn update_server_state(&mut self, json: &str) {
-        let message: MessageTypes = serde_json::from_str(json).unwrap();
+    ///Returns whether the message should be relayed to everyone
+    fn update_server_state(&mut self, json: &str) -> ReadResult<bool> {
+        let message: MessageTypes =
+            serde_json::from_str(json).map_err(|e| DecodeError::BadJson(e.to_string()))?;
+
+        //The server sends these itself when it's time, so clients can't trigger them for everyone
+        let mut relay = !matches!(
+            message,
+            MessageTypes::AutoStartTimerMessage { .. }
+                | MessageTypes::ClientLeftMessage { .. }
+                | MessageTypes::LoadLobbyMessage {}
+                | MessageTypes::RaceTimeoutMessage { .. }
+                | MessageTypes::StartRaceMessage {}
+        );
+
+        //Like in C#, a client can only send messages about itself and its own players
+        if let Some(guid) = message.acting_client() {
+            let guid = guid_to_vec(guid.to_owned())?;
+            if !self.sent_by(&guid) {
+                println!(
+                    "{} sent a {} for another client",
+                    self.stream.origin,
+                    message.as_string()
+                );
+                return Ok(false);
+            }
+        }
+
         match message {
-            MessageTypes::AutoStartTimerMessage { enabled } => todo!(),
+            //Only the server decides when the timer runs
+            MessageTypes::AutoStartTimerMessage { .. } => {
+                println!("A client tried to change the auto start timer")
+            }
             MessageTypes::ChangedReadyMessage {
                 client_guid,
                 ctrl_type,
                 ready,
             } => {
-                self.clock.lobby.start();
-                let vecter = guid_to_vec(client_guid);
+                let vecter = guid_to_vec(client_guid)?;
                 match self.current_player(vecter, &ctrl_type) {
                     Some(index) => {
                         self.players[index].ready_to_race = ready;
                     }
                     None => println!("No such player"),
                 }
+                self.check_ready();
             }
             MessageTypes::CharacterChangedMessage {
                 client_guid,
                 ctrl_type,
                 new_character,
             } => {
-                let vecter = guid_to_vec(client_guid);
+                let vecter = guid_to_vec(client_guid)?;
                 match self.current_player(vecter, &ctrl_type) {
+                    Some(_) if !self.allowed_character(new_character) => {
+                        relay = false;
+                        println!("A player tried to set character to {new_character} but the character's tier is not allowed");
+                        self.chat_to(
+                            &format!(
+                                "You can't use this character - {}",
+                                self.match_settings.allowed_tiers.text()
+                            ),
+                            self.stream.origin,
+                        );
+                    }
                     Some(index) => {
-                        // ! validate player
                         self.players[index].char_id = new_character;
                     }
                     None => println!("Player does not exist"),
                 }
             }
-            // TODO meme everyone into shrek
-            MessageTypes::ChatMessage { from, r#type, text } => {}
+            //Chat starting with / is a command for the server, nobody else gets to see it
+            MessageTypes::ChatMessage { text, .. } => match text.strip_prefix('/') {
+                Some(command) => {
+                    relay = false;
+                    self.chat_commands
+                        .push((self.stream.origin, command.to_owned()));
+                }
+                None => relay = self.chat(&text),
+            },
             MessageTypes::CheckpointPassedMessage {
                 client_guid,
                 ctrl_type,
-                lap_time,
-            } => {}
+                ..
+            } => {
+                let vecter = guid_to_vec(client_guid)?;
+                match self.current_player(vecter, &ctrl_type) {
+                    //As long as everyone is racing the player gets more time
+                    Some(index) if self.players.iter().all(|e| e.is_racing) => {
+                        let player = &mut self.players[index];
+                        player.race_timeout.reset();
+                        player.race_timeout.start();
+
+                        if player.timeout_warned {
+                            player.timeout_warned = false;
+                            self.send_race_timeout(index, 0.0);
+                        }
+                    }
+                    Some(_) => {}
+                    None => println!("Received CheckpointPassedMessage for invalid player"),
+                }
+            }
             MessageTypes::ClientJoinedMessage {
                 client_guid,
                 client_name,
             } => {
                 let socket = self.stream.origin;
-                let vecter = guid_to_vec(client_guid);
+                if let Some(ban) = self.bans.find_guid(&client_guid) {
+                    let message = ban.message();
+                    println!("Banned client {client_name:?} tried to join");
+                    self.disconnect(socket, &message);
+                    return Ok(false);
+                }
+
+                let is_admin = self
+                    .config
+                    .admins
+                    .iter()
+                    .any(|e| e.eq_ignore_ascii_case(&client_guid));
+                let vecter = guid_to_vec(client_guid)?;
+
+                //Admins are recognised by their GUID, so nobody else may join with it
+                if self.sending_client().is_some() {
+                    println!("{client_name:?} tried to join again from {socket}");
+                    return Ok(false);
+                }
+                if self.current_client(vecter.clone()).is_some() {
+                    println!("{client_name:?} tried to join with a GUID that's already taken");
+                    self.disconnect(socket, "Someone with your GUID is already in the match");
+                    return Ok(false);
+                }
 
                 self.chat_all(&format!("{:?}, Has Joined The Match", client_name));
                 self.chat_to("Welcome", socket);
-                // ! valid characters
                 self.chat_to(
                     &format!("Our Message of the day is {}", self.motd.text),
                     socket,
                 );
-                // ! Add support to send what characters are allowed
+                self.chat_to(self.match_settings.allowed_tiers.text(), socket);
 
                 self.clients.push(Client {
                     guid: vecter,
@@ -375,51 +1156,72 @@ impl Server {
                     connection: self.stream.origin,
                     is_loading: false,
                     wants_lobby: false,
-                    counter: 0,
+                    is_admin,
+                    chat_times: vec![],
                 });
             }
-            MessageTypes::ClientLeftMessage { client_guid } => {}
+            //Only the server says who left, when their connection is gone
+            MessageTypes::ClientLeftMessage { .. } => {
+                println!("{} tried to send a ClientLeftMessage", self.stream.origin)
+            }
             MessageTypes::DoneRacingMessage {
                 client_guid,
                 ctrl_type,
-                race_time,
-                disqualified,
+                ..
             } => {
-                let vecter = guid_to_vec(client_guid);
+                let vecter = guid_to_vec(client_guid)?;
                 match self.current_player(vecter, &ctrl_type) {
-                    Some(index) => {
-                        self.players[index].is_racing = false;
-                        // ! reset race timeout
-                        // ! kick everyone
-                    }
+                    Some(index) => self.finish_race(index),
                     None => println!("Who are you talking about"),
                 }
             }
-            MessageTypes::LoadLobbyMessage {} => {}
+            MessageTypes::LoadLobbyMessage {} => match self.sending_client() {
+                Some(index) => self.vote_lobby(index),
+                None => println!("Received LoadLobbyMessage from unknown client"),
+            },
             MessageTypes::LoadRaceMessage {} => {}
             MessageTypes::PlayerJoinedMessage {
                 client_guid,
                 ctrl_type,
                 initial_character,
             } => {
-                let socket = self.stream.origin;
-
-                let vecter = guid_to_vec(client_guid);
+                let vecter = guid_to_vec(client_guid)?;
                 match self.current_client(vecter.clone()) {
                     None => println!("A Player that is not a Client attempted to join"),
+                    Some(_) if !self.allowed_character(initial_character) => {
+                        relay = false;
+                        self.chat_to(
+                            &format!(
+                                "You cannot join with this character - {}",
+                                self.match_settings.allowed_tiers.text()
+                            ),
+                            self.stream.origin,
+                        );
+                    }
                     Some(_) => {
                         println!("New player");
-                        // ! Verify player is valid
-                        //self.chat_to("You can't join", socket);
                         self.players.push(Player {
                             guid: vecter,
                             ctrl_type,
                             char_id: initial_character,
                             ready_to_race: false,
                             is_racing: false,
-                            race_timeout: Stopwatch {},
+                            race_timeout: Timer::default(),
+                            timeout_warned: false,
                             has_timed_out: false,
-                        })
+                        });
+
+                        if self.enough_players()
+                            && !self.clock.auto_start.running
+                            && self.match_settings.auto_start_time > 0
+                        {
+                            println!(
+                                "Match will auto start in {} seconds.",
+                                self.match_settings.auto_start_time
+                            );
+                            self.start_auto_start_timer();
+                        }
+                        self.check_ready();
                     }
                 }
             }
@@ -427,10 +1229,12 @@ impl Server {
                 client_guid,
                 ctrl_type,
             } => {
-                let vecter = guid_to_vec(client_guid);
+                let vecter = guid_to_vec(client_guid)?;
                 match self.current_player(vecter, &ctrl_type) {
                     Some(index) => {
                         self.players.remove(index);
+                        self.check_auto_start_players();
+                        self.check_ready();
                     }
                     None => println!("This guy didn't exist anyway"),
                 }
@@ -440,76 +1244,594 @@ impl Server {
                 ctrl_type,
                 race_time,
                 race_position,
-            } => {}
-            MessageTypes::RaceTimeoutMessage {
-                client_guid,
-                ctrl_type,
-                time,
-            } => {}
-            MessageTypes::SettingsChanged { new_match_settings } => {
-                self.match_settings = new_match_settings;
+            } => {
+                let vecter = guid_to_vec(client_guid)?;
+                match self.current_player(vecter, &ctrl_type) {
+                    Some(index) => {
+                        let guid = &self.players[index].guid;
+                        if let Some(client) = self.clients.iter().find(|e| &e.guid == guid) {
+                            println!(
+                                "{} finished in position {race_position} ({race_time:.2}s)",
+                                client.name
+                            );
+                        }
+                        self.finish_race(index);
+                    }
+                    None => println!("Received RaceFinishedMessage for invalid player"),
+                }
             }
-            MessageTypes::StartRaceMessage {} => {
+            MessageTypes::RaceTimeoutMessage { .. } => {}
+            //Only admins get to change the settings, anyone else is ignored like in C#
+            MessageTypes::SettingsChanged { new_match_settings } => {
+                let socket = self.stream.origin;
                 match self.sending_client() {
-                    Some(index) => {
-                        // ! smth smth timers
-                        for player in &mut self.players {
-                            player.is_racing = true;
-                            //player.race_timeout
+                    Some(index) if self.clients[index].is_admin => {
+                        //Everyone gets the settings from change_settings, after the tiers are corrected
+                        relay = false;
+                        if let Err(reason) = self.change_settings(|e| *e = new_match_settings) {
+                            println!("Invalid match settings from an admin: {reason}");
+                            self.chat_to(&format!("Settings not changed - {reason}"), socket);
                         }
                     }
-                    None => println!("What?"),
+                    _ => {
+                        relay = false;
+                        println!("A player tried to change match settings");
+                        self.chat_to("Only admins can change the match settings", socket);
+                    }
+                }
+            }
+            //A client is done loading the stage
+            MessageTypes::StartRaceMessage {} => match self.sending_client() {
+                Some(index) if self.clients[index].is_loading => {
+                    self.clients[index].is_loading = false;
+                    self.check_loaded();
+                }
+                Some(_) => {}
+                None => println!("What?"),
+            },
+        }
+
+        Ok(relay)
+    }
+
+    ///Tell everyone to load the stage and stop the timers that would have started the race
+    pub fn load_race(&mut self) {
+        self.clock.lobby.reset();
+        self.stop_auto_start_timer();
+        self.send_new(MessageTypes::LoadRaceMessage {});
+        self.in_race = true;
+
+        for player in self.players.iter_mut() {
+            player.ready_to_race = false;
+        }
+
+        //Wait for every client to load the stage
+        for client in self.clients.iter_mut() {
+            client.is_loading = true;
+        }
+        self.clock.stage_load_timeout.start();
+    }
+
+    ///Start the race once nobody is loading the stage anymore
+    fn check_loaded(&mut self) {
+        if !self.clock.stage_load_timeout.running {
+            return;
+        }
+
+        match self.clients.iter().filter(|e| e.is_loading).count() {
+            0 => self.start_race(),
+            left => println!("Waiting for {left} client(s) to load"),
+        }
+    }
+
+    ///Everyone loaded the stage (or took too long), let the race begin
+    fn start_race(&mut self) {
+        println!("Starting race!");
+        self.send_new(MessageTypes::StartRaceMessage {});
+        self.clock.stage_load_timeout.reset();
+
+        for player in self.players.iter_mut() {
+            player.is_racing = true;
+            player.race_timeout.reset();
+            player.race_timeout.start();
+        }
+    }
+
+    ///A player is done racing, either by finishing or being disqualified.
+    ///Once nobody is racing anymore everyone goes back to the lobby after a while.
+    fn finish_race(&mut self, index: usize) {
+        let player = &mut self.players[index];
+        if !player.is_racing {
+            return;
+        }
+        player.is_racing = false;
+        player.race_timeout.reset();
+
+        self.send_race_timeout(index, 0.0);
+
+        match self.players.iter().filter(|e| e.is_racing).count() {
+            0 => {
+                println!("All players are done racing.");
+                if self.match_settings.auto_return_time > 0 {
+                    self.chat_all(&format!(
+                        "Returning to lobby in {} seconds",
+                        self.match_settings.auto_return_time
+                    ));
+                    self.clock.back_to_lobby_timer.start();
                 }
             }
+            racing => println!("{racing} player(s) still racing"),
+        }
+    }
+
+    ///Tell every client about the current match settings
+    fn send_settings(&mut self) {
+        self.send_new(MessageTypes::SettingsChanged {
+            new_match_settings: self.match_settings.clone(),
+        });
+    }
+
+    ///Write the match settings back to their file so they survive a restart
+    fn save_match_settings(&self) {
+        if let Err(e) = save_file(MATCH_SETTINGS_FILE, &self.match_settings) {
+            println!("Could not save match settings: {e}");
+        }
+    }
+
+    ///Load the match settings from their file again and send them to everyone
+    pub fn reload_settings(&mut self) -> Result<(), String> {
+        let match_settings: MatchConfig =
+            load_file(MATCH_SETTINGS_FILE).map_err(|e| e.to_string())?;
+        match_settings.validate(self.config.stage_count)?;
+
+        self.match_settings = match_settings;
+        self.correct_player_tiers();
+        self.send_settings();
+        Ok(())
+    }
+
+    ///Pick the stage for the next race, depending on the stage rotation mode.
+    ///Returns whether the stage changed.
+    fn rotate_stage(&mut self) -> bool {
+        let stage_count = self.config.stage_count.max(1);
+        let current = self.match_settings.stage_id;
+
+        let stage = match self.match_settings.stage_rotation_mode {
+            StageRotationMode::None => return false,
+            StageRotationMode::Sequenced => {
+                println!("Picking next stage");
+                (current + 1).rem_euclid(stage_count)
+            }
+            StageRotationMode::Random => {
+                println!("Picking random stage");
+                //Pick from every stage except the current one
+                let others = usize::try_from(stage_count - 1).unwrap_or(0);
+                let stage = random(others) as i32;
+                match stage >= current {
+                    true if stage_count > 1 => stage + 1,
+                    _ => stage,
+                }
+            }
+        };
+        if stage == current {
+            return false;
+        }
+
+        self.match_settings.stage_id = stage;
+        true
+    }
+
+    ///Pick the character tiers for the next race, depending on the tier rotation mode.
+    ///Returns whether the tiers changed.
+    fn rotate_tiers(&mut self) -> bool {
+        let rotation = [
+            AllowedTiers::NormalOnly,
+            AllowedTiers::OddOnly,
+            AllowedTiers::HyperspeedOnly,
+        ];
+
+        let tiers = match self.match_settings.tier_rotation_mode {
+            TierRotationMode::None => return false,
+            TierRotationMode::Cycle => match self.match_settings.allowed_tiers {
+                AllowedTiers::NormalOnly => AllowedTiers::OddOnly,
+                AllowedTiers::OddOnly => AllowedTiers::HyperspeedOnly,
+                _ => AllowedTiers::NormalOnly,
+            },
+            TierRotationMode::Random => rotation[random(rotation.len())],
+            TierRotationMode::WeightedRandom => {
+                let choices = [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 2];
+                rotation[choices[random(choices.len())]]
+            }
+        };
+        if tiers == self.match_settings.allowed_tiers {
+            return false;
+        }
+
+        self.match_settings.allowed_tiers = tiers;
+        self.correct_player_tiers();
+        self.chat_all(tiers.text());
+        true
+    }
+
+    ///Whether a character may be used with the current allowed tiers
+    fn allowed_character(&self, character: i32) -> bool {
+        CharacterTier::of(character)
+            .is_some_and(|tier| self.match_settings.allowed_tiers.allows(tier))
+    }
+
+    ///Switch every player using a character that's no longer allowed to the first one that is
+    fn correct_player_tiers(&mut self) {
+        let allowed_tiers = self.match_settings.allowed_tiers;
+        let Some(allowed) = CHARACTER_TIERS
+            .iter()
+            .position(|e| allowed_tiers.allows(*e))
+        else {
+            return;
+        };
+        let allowed = allowed as i32;
+
+        for index in 0..self.players.len() {
+            if self.allowed_character(self.players[index].char_id) {
+                continue;
+            }
+            let player = &mut self.players[index];
+            player.char_id = allowed;
+
+            let guid = player.guid.clone();
+            let ctrl_type = player.ctrl_type;
+            let Some(client) = self.clients.iter().find(|e| e.guid == guid) else {
+                continue;
+            };
+            let addr = client.connection;
+
+            self.send_new(MessageTypes::CharacterChangedMessage {
+                client_guid: vec_to_guid(&guid),
+                ctrl_type,
+                new_character: allowed,
+            });
+            self.chat_to(
+                "Your character is not allowed and has been automatically changed.",
+                addr,
+            );
+        }
+    }
+
+    ///Log a chat message from the current sender and decide if everyone else gets to see it
+    fn chat(&mut self, text: &str) -> bool {
+        let socket = self.stream.origin;
+        let Some(index) = self.sending_client() else {
+            println!("Chat message from unknown client {socket}");
+            return false;
+        };
+        let name = self.clients[index].name.clone();
+
+        if self.muted.contains(&self.clients[index].guid) {
+            println!("[{}] {name} (muted): {text}", timestamp());
+            self.chat_to("You are muted", socket);
+            return false;
+        }
+        //Admins are trusted to not spam or swear
+        let is_admin = self.clients[index].is_admin;
+        if !is_admin && self.flooding(index) {
+            println!("[{}] {name} (flooding): {text}", timestamp());
+            self.chat_to("You are sending messages too fast", socket);
+            return false;
+        }
+
+        let lowercase = text.to_lowercase();
+        let filtered = self
+            .config
+            .chat_filter
+            .iter()
+            .any(|e| lowercase.contains(&e.to_lowercase()));
+        if filtered && !is_admin {
+            println!("[{}] {name} (filtered): {text}", timestamp());
+            self.chat_to("Your message was blocked by the chat filter", socket);
+            return false;
+        }
+
+        println!("[{}] {name}: {text}", timestamp());
+        if lowercase.contains("shrek") {
+            self.shrek(index);
+        }
+        true
+    }
+
+    ///Whether a client sent more chat messages than the rate limit allows, counts this one if not
+    fn flooding(&mut self, index: usize) -> bool {
+        let limit = self.config.chat_rate_limit;
+        if limit == 0 {
+            return false;
+        }
+
+        let times = &mut self.clients[index].chat_times;
+        times.retain(|e| e.elapsed() < CHAT_RATE_WINDOW);
+        if times.len() >= limit {
+            return true;
+        }
+        times.push(Instant::now());
+        false
+    }
+
+    ///The C# easter egg, every player of a client that mentions shrek becomes Ogre
+    fn shrek(&mut self, index: usize) {
+        if !self.allowed_character(SHREK_CHARACTER) {
+            return;
+        }
+
+        let guid = self.clients[index].guid.clone();
+        let mut changed = vec![];
+        for player in self.players.iter_mut().filter(|e| e.guid == guid) {
+            player.char_id = SHREK_CHARACTER;
+            changed.push(player.ctrl_type);
+        }
+
+        for ctrl_type in changed {
+            self.send_new(MessageTypes::CharacterChangedMessage {
+                client_guid: vec_to_guid(&guid),
+                ctrl_type,
+                new_character: SHREK_CHARACTER,
+            });
+        }
+    }
+
+    ///A client wants to go back to the lobby, everyone goes once enough clients voted for it
+    fn vote_lobby(&mut self, index: usize) {
+        if !self.in_race {
+            println!(
+                "{} voted to return to the lobby outside of a race",
+                self.clients[index].name
+            );
+            return;
+        }
+        if self.clients[index].wants_lobby {
+            return;
+        }
+        self.clients[index].wants_lobby = true;
+
+        let required = (self.clients.len() as f32 * self.match_settings.vote_ratio) as usize;
+        let votes = self.clients.iter().filter(|e| e.wants_lobby).count();
+
+        if votes >= required {
+            self.chat_all("Returning to lobby by user vote.");
+            self.return_to_lobby();
+        } else {
+            let name = self.clients[index].name.clone();
+            self.chat_all(&format!(
+                "{name} wants to return to the lobby. {} more vote(s) needed.",
+                required - votes
+            ));
+        }
+    }
+
+    ///Send everyone back to the lobby and get them ready for the next race
+    pub fn return_to_lobby(&mut self) {
+        if !self.in_race {
+            println!("Already in lobby");
+            return;
+        }
+
+        println!("Returned to lobby");
+        self.in_race = false;
+        self.send_new(MessageTypes::LoadLobbyMessage {});
+
+        self.clock.back_to_lobby_timer.reset();
+        self.clock.stage_load_timeout.reset();
+
+        for player in self.players.iter_mut() {
+            player.is_racing = false;
+            player.ready_to_race = false;
+            player.race_timeout.reset();
+            player.timeout_warned = false;
+            player.has_timed_out = false;
+        }
+        for client in self.clients.iter_mut() {
+            client.is_loading = false;
+            client.wants_lobby = false;
+        }
+
+        let stage_changed = self.rotate_stage();
+        let tiers_changed = self.rotate_tiers();
+        if stage_changed || tiers_changed {
+            self.send_settings();
+            self.save_match_settings();
+        }
+
+        if self.enough_players() && self.match_settings.auto_start_time > 0 {
+            println!("There are still players, auto start timer started");
+            self.start_auto_start_timer();
+        }
+    }
+
+    ///Show everyone how long a player has left before being disqualified, 0 hides it
+    fn send_race_timeout(&mut self, index: usize, time: f32) {
+        let player = &self.players[index];
+        self.send_new(MessageTypes::RaceTimeoutMessage {
+            client_guid: vec_to_guid(&player.guid),
+            ctrl_type: player.ctrl_type,
+            time,
+        });
+    }
+
+    ///Warn players that haven't passed a checkpoint in a while, then disqualify them
+    fn race_timeouts(&mut self) {
+        if self.match_settings.disqualification_time <= 0 {
+            return;
+        }
+        let disqualification_time = self.match_settings.disqualification_time as f32;
+
+        for index in 0..self.players.len() {
+            let player = &mut self.players[index];
+            if !player.is_racing {
+                continue;
+            }
+            let elapsed = player.race_timeout.now().as_secs_f32();
+
+            if !player.timeout_warned && elapsed > disqualification_time / 2.0 {
+                player.timeout_warned = true;
+                self.send_race_timeout(index, disqualification_time / 2.0);
+            }
+
+            if elapsed > disqualification_time {
+                println!("A player was too slow to race and has been disqualified.");
+                self.players[index].has_timed_out = true;
+                self.finish_race(index);
+
+                let player = &self.players[index];
+                self.send_new(MessageTypes::DoneRacingMessage {
+                    client_guid: vec_to_guid(&player.guid),
+                    ctrl_type: player.ctrl_type,
+                    race_time: 0.0,
+                    disqualified: true,
+                });
+            }
+        }
+    }
+
+    ///Start the lobby countdown once every player is ready, stop it as soon as someone isn't
+    fn check_ready(&mut self) {
+        if self.in_race {
+            return;
+        }
+
+        let all_ready = !self.players.is_empty() && self.players.iter().all(|e| e.ready_to_race);
+        if all_ready && !self.clock.lobby.running {
+            println!("All players ready, timer started");
+            self.clock.lobby.start();
+        } else if !all_ready && self.clock.lobby.running {
+            println!("Not all players are ready, timer stopped");
+            self.clock.lobby.reset();
         }
     }
 
+    ///Whether there's enough players for the race to start on its own
+    fn enough_players(&self) -> bool {
+        let min_players = usize::try_from(self.match_settings.auto_start_min_players).unwrap_or(0);
+        self.players.len() >= min_players
+    }
+
+    fn start_auto_start_timer(&mut self) {
+        self.clock.auto_start.reset();
+        self.clock.auto_start.start();
+        self.send_new(MessageTypes::AutoStartTimerMessage { enabled: true });
+    }
+
+    fn stop_auto_start_timer(&mut self) {
+        self.clock.auto_start.reset();
+        self.send_new(MessageTypes::AutoStartTimerMessage { enabled: false });
+    }
+
+    ///Stop the auto start timer if players left and there's no longer enough of them
+    fn check_auto_start_players(&mut self) {
+        if !self.enough_players() && self.clock.auto_start.running {
+            println!("Too few players, match auto start timer stopped");
+            self.stop_auto_start_timer();
+        }
+    }
+
+    ///Seconds until the race starts on its own, 0 if the timer isn't running
+    fn auto_start_time_left(&mut self) -> f32 {
+        if !self.clock.auto_start.running {
+            return 0.0;
+        }
+
+        let auto_start_time = self.match_settings.auto_start_time as f32;
+        auto_start_time - self.clock.auto_start.now().as_secs_f32()
+    }
+
     fn timers(&mut self) {
+        for response in self.server_list.responses() {
+            match response.result {
+                Ok(body) => println!("Server list at '{}' said: {body}", response.url),
+                Err(error) => println!(
+                    "Failed adding server to server list at '{}': {error}",
+                    response.url
+                ),
+            }
+        }
+
+        if self.config.public {
+            self.server_list.ping();
+        }
+
         if self.clock.lobby.timeout(Duration::from_secs(3)) {
-            self.clock.lobby.reset();
-            self.clock.stage_load_timeout.start();
+            println!("The race has been started by all players being ready.");
+            self.chat_all("Everyone is ready, starting the race!");
+            self.load_race();
+        }
 
-            self.send_new(MessageTypes::LoadRaceMessage {})
+        let auto_start_time = u64::try_from(self.match_settings.auto_start_time).unwrap_or(0);
+        if self
+            .clock
+            .auto_start
+            .timeout(Duration::from_secs(auto_start_time))
+        {
+            println!("The race has been automatically started.");
+            self.load_race();
         }
 
-        if self.clock.stage_load_timeout.timeout(Duration::from_secs(20)) {
-            self.clock.stage_load_timeout.reset();
+        self.race_timeouts();
+
+        let auto_return_time = u64::try_from(self.match_settings.auto_return_time).unwrap_or(0);
+        if self
+            .clock
+            .back_to_lobby_timer
+            .timeout(Duration::from_secs(auto_return_time))
+        {
+            self.return_to_lobby();
+        }
 
-            self.send_new(MessageTypes::StartRaceMessage {  })
+        //Don't wait forever on clients that never finish loading
+        if self.clock.stage_load_timeout.timeout(STAGE_LOADING_TIMEOUT) {
+            let stragglers: Vec<(SocketAddr, String)> = self
+                .clients
+                .iter()
+                .filter(|e| e.is_loading)
+                .map(|e| (e.connection, e.name.clone()))
+                .collect();
+
+            for (addr, name) in stragglers {
+                println!("{name} took too long to load the race");
+                if self.config.kick_slow_loaders {
+                    self.kick(addr, "Took too long to load the race");
+                }
+            }
+
+            //Kicking the last one already started the race
+            if self.clock.stage_load_timeout.running {
+                self.start_race();
+            }
         }
     }
 }
 
-fn guid_to_vec(guid: String) -> Vec<u8> {
+fn guid_to_vec(guid: String) -> ReadResult<Vec<u8>> {
+    if guid.len() != 36 || !guid.is_ascii() {
+        return Err(DecodeError::BadGuid(guid));
+    }
+
+    let hex = |range: std::ops::Range<usize>| -> ReadResult<Vec<u8>> {
+        range
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&guid[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| DecodeError::BadGuid(guid.clone()))
+    };
+
     let mut sum: Vec<u8> = Vec::with_capacity(16);
 
-    let mut first: Vec<u8> = (0..8)
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&guid[i..i + 2], 16).unwrap())
-        .collect();
+    let mut first: Vec<u8> = hex(0..8)?;
     first.reverse();
 
-    let mut second: Vec<u8> = (9..13)
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&guid[i..i + 2], 16).unwrap())
-        .collect();
+    let mut second: Vec<u8> = hex(9..13)?;
     second.reverse();
 
-    let mut third: Vec<u8> = (14..18)
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&guid[i..i + 2], 16).unwrap())
-        .collect();
+    let mut third: Vec<u8> = hex(14..18)?;
     third.reverse();
 
-    let mut forth: Vec<u8> = (19..23)
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&guid[i..i + 2], 16).unwrap())
-        .collect();
+    let mut forth: Vec<u8> = hex(19..23)?;
 
-    let mut fifth: Vec<u8> = (24..36)
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&guid[i..i + 2], 16).unwrap())
-        .collect();
+    let mut fifth: Vec<u8> = hex(24..36)?;
 
     sum.append(&mut first);
     sum.append(&mut second);
@@ -518,5 +1840,441 @@ fn guid_to_vec(guid: String) -> Vec<u8> {
     sum.append(&mut fifth);
 
     println!("{guid}");
-    sum
+    Ok(sum)
+}
+
+///Turn the bytes of a C# Guid back into its string form
+fn vec_to_guid(guid: &[u8]) -> String {
+    let hex = |bytes: &[u8]| -> String { bytes.iter().map(|e| format!("{e:02x}")).collect() };
+
+    let mut first = guid[0..4].to_vec();
+    first.reverse();
+
+    let mut second = guid[4..6].to_vec();
+    second.reverse();
+
+    let mut third = guid[6..8].to_vec();
+    third.reverse();
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex(&first),
+        hex(&second),
+        hex(&third),
+        hex(&guid[8..10]),
+        hex(&guid[10..16])
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{buffer::HEADER_SIZE, to_byte};
+
+    ///A private server on a free port, with every optional setting left out
+    fn server() -> Server {
+        let config = r#"{
+            "public": false,
+            "servers": [],
+            "ip": "127.0.0.1",
+            "port": 0,
+            "max_players": 10,
+            "admin_password": "hunter2",
+            "enabled_connections": []
+        }"#;
+        let matches = r#"{
+            "StageId": 0,
+            "Laps": 2,
+            "AICount": 0,
+            "AISkill": 1,
+            "AutoStartTime": 60,
+            "AutoStartMinPlayers": 2,
+            "AutoReturnTime": 15,
+            "VoteRatio": 1.0,
+            "StageRotationMode": 0
+        }"#;
+
+        Server::new(
+            serde_json::from_str(config).unwrap(),
+            serde_json::from_str(matches).unwrap(),
+            Motd {
+                text: "Hello".to_owned(),
+            },
+            BanList::default(),
+        )
+    }
+
+    ///Add a client that joined from `port` on localhost, returns its adress
+    fn join(server: &mut Server, name: &str, port: u16) -> SocketAddr {
+        let connection = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), port);
+        server.clients.push(Client {
+            guid: vec![to_byte(port.into()); 16],
+            name: name.to_owned(),
+            connection,
+            is_loading: false,
+            wants_lobby: false,
+            is_admin: false,
+            chat_times: vec![],
+        });
+        connection
+    }
+
+    #[test]
+    fn login_needs_the_password() {
+        let mut server = server();
+        let addr = join(&mut server, "Sanic", 1);
+
+        assert!(server.login(addr, "hunter3").is_err());
+        assert!(!server.is_admin(addr));
+        assert!(server.login(addr, "hunter2").is_ok());
+        assert!(server.is_admin(addr));
+    }
+
+    #[test]
+    fn login_locks_out_after_wrong_passwords() {
+        let mut server = server();
+        let addr = join(&mut server, "Sanic", 1);
+        for _ in 0..LOGIN_ATTEMPTS {
+            assert_eq!(server.login(addr, "guess").unwrap_err(), "Wrong password");
+        }
+
+        //Even the right password is refused now
+        assert!(server.login(addr, "hunter2").is_err());
+        assert!(!server.is_admin(addr));
+
+        //Reconnecting from another port of the same IP doesn't reset it
+        let other = join(&mut server, "Tails", 2);
+        assert!(server.login(other, "hunter2").is_err());
+
+        //Once the lockout is over the password works again
+        let old = Instant::now() - LOGIN_LOCKOUT;
+        for failure in server.login_failures.values_mut().flatten() {
+            *failure = old;
+        }
+        assert!(server.login(addr, "hunter2").is_ok());
+    }
+
+    ///Give a joined client a player and a connection we can look into
+    fn play(server: &mut Server, addr: SocketAddr) {
+        let guid = server
+            .clients
+            .iter()
+            .find(|e| e.connection == addr)
+            .unwrap()
+            .guid
+            .clone();
+        server.players.push(Player {
+            guid,
+            ctrl_type: 0,
+            char_id: 0,
+            ready_to_race: false,
+            is_racing: false,
+            race_timeout: Timer::default(),
+            timeout_warned: false,
+            has_timed_out: false,
+        });
+        let mut connection = Connection::new(addr);
+        connection.establish();
+        server.connections.insert(addr, connection);
+    }
+
+    ///Pretend `addr` sent a message with `write` as its data
+    fn receive(server: &mut Server, addr: SocketAddr, write: impl Fn(&mut Buffer)) {
+        let mut buffer = Buffer::default();
+        write(&mut buffer);
+        buffer.write_header(Header::UserUnreliable);
+        server.stream = Stream::new(&buffer.message(), addr).unwrap();
+        server.buffer = Buffer::default();
+    }
+
+    fn movement(server: &mut Server, from: SocketAddr, guid: Vec<u8>) {
+        receive(server, from, |buffer| {
+            buffer.write_game_header(GameHeader::PlayerMovementMessage);
+            buffer.write_f32(&1.0);
+            buffer.write_guid(guid.clone());
+            buffer.write_byte(0);
+        });
+        server.relay_data().unwrap();
+    }
+
+    #[test]
+    fn movement_is_only_relayed_for_the_senders_players() {
+        let mut server = server();
+        let sanic = join(&mut server, "Sanic", 1);
+        let tails = join(&mut server, "Tails", 2);
+        play(&mut server, sanic);
+        play(&mut server, tails);
+        let sanic_guid = server.clients[0].guid.clone();
+        let tails_guid = server.clients[1].guid.clone();
+
+        movement(&mut server, sanic, sanic_guid);
+        assert_eq!(server.connections.get_mut(&tails).unwrap().flush().len(), 1);
+        assert!(server
+            .connections
+            .get_mut(&sanic)
+            .unwrap()
+            .flush()
+            .is_empty());
+
+        movement(&mut server, sanic, tails_guid);
+        assert!(server
+            .connections
+            .get_mut(&tails)
+            .unwrap()
+            .flush()
+            .is_empty());
+    }
+
+    #[test]
+    fn clients_cant_say_someone_left() {
+        let mut server = server();
+        let sanic = join(&mut server, "Sanic", 1);
+        let json = format!(
+            r#"{{"$type":"ClientLeftMessage","ClientGuid":"{}"}}"#,
+            vec_to_guid(&server.clients[0].guid)
+        );
+
+        server.stream.origin = sanic;
+        assert!(!server.update_server_state(&json).unwrap());
+        assert_eq!(server.clients.len(), 1);
+    }
+
+    #[test]
+    fn init_message_bools_are_single_bits() {
+        let mut server = server();
+        let sanic = join(&mut server, "A", 1);
+        play(&mut server, sanic);
+        server.players[0].ready_to_race = true;
+        server.players[0].char_id = 3;
+        server.in_race = true;
+
+        server.write_init_message();
+
+        //Game header, then the client with its guid and name
+        let mut expected = vec![0x01, 1, 0, 0, 0, 16, 0, 0, 0];
+        expected.extend([1; 16]);
+        expected.extend([1, b'A']);
+        //The player with its guid and controls
+        expected.extend([1, 0, 0, 0, 16, 0, 0, 0]);
+        expected.extend([1; 16]);
+        expected.extend([0, 0, 0, 0]);
+        //Its ready bit, everything after it is one bit up: character 3, then the settings
+        expected.extend([0x07, 0, 0, 0]);
+        expected.extend([0, 0, 0, 0, 0x04, 0, 0, 0, 0, 0, 0, 0, 0x02, 0, 0, 0]);
+        expected.extend([0x78, 0, 0, 0, 0x04, 0, 0, 0, 0x1e, 0, 0, 0]);
+        expected.extend([0, 0, 0, 0x7f, 0, 0, 0, 0]);
+        //In race bit two bits up, then no auto start time
+        expected.extend([0x02, 0, 0, 0, 0]);
+
+        assert_eq!(server.buffer.payload[HEADER_SIZE..], expected);
+        //794 bits
+        assert_eq!(server.buffer.payload[3..5], [0x1a, 0x03]);
+    }
+
+    ///Pretend `addr` sent a chat message, returns whether everyone else gets to see it
+    fn chat(server: &mut Server, addr: SocketAddr, text: &str) -> bool {
+        server.stream.origin = addr;
+        server.chat(text)
+    }
+
+    #[test]
+    fn muted_clients_cant_chat() {
+        let mut server = server();
+        let sanic = join(&mut server, "Sanic", 1);
+
+        server.set_muted(sanic, true).unwrap();
+        assert!(!chat(&mut server, sanic, "hello"));
+        server.set_muted(sanic, false).unwrap();
+        assert!(chat(&mut server, sanic, "hello"));
+    }
+
+    #[test]
+    fn chat_is_rate_limited() {
+        let mut server = server();
+        server.config.chat_rate_limit = 2;
+        let sanic = join(&mut server, "Sanic", 1);
+        let tails = join(&mut server, "Tails", 2);
+
+        assert!(chat(&mut server, sanic, "one"));
+        assert!(chat(&mut server, sanic, "two"));
+        assert!(!chat(&mut server, sanic, "three"));
+        //Every client has its own limit
+        assert!(chat(&mut server, tails, "one"));
+
+        //Messages older than the window don't count anymore
+        let old = Instant::now() - CHAT_RATE_WINDOW;
+        server.clients[0].chat_times = vec![old, old];
+        assert!(chat(&mut server, sanic, "four"));
+
+        server.config.chat_rate_limit = 0;
+        for _ in 0..10 {
+            assert!(chat(&mut server, tails, "no limit"));
+        }
+    }
+
+    #[test]
+    fn chat_filter_blocks_any_case() {
+        let mut server = server();
+        server.config.chat_filter = vec!["Sonic".to_owned()];
+        let sanic = join(&mut server, "Sanic", 1);
+
+        assert!(!chat(&mut server, sanic, "i like sonic"));
+        assert!(!chat(&mut server, sanic, "SONICS"));
+        assert!(chat(&mut server, sanic, "i like sanic"));
+    }
+
+    #[test]
+    fn admins_skip_the_rate_limit_and_filter_but_not_mute() {
+        let mut server = server();
+        server.config.chat_rate_limit = 1;
+        server.config.chat_filter = vec!["sonic".to_owned()];
+        let sanic = join(&mut server, "Sanic", 1);
+        server.clients[0].is_admin = true;
+
+        assert!(chat(&mut server, sanic, "one"));
+        assert!(chat(&mut server, sanic, "two"));
+        assert!(chat(&mut server, sanic, "sonic"));
+
+        server.set_muted(sanic, true).unwrap();
+        assert!(!chat(&mut server, sanic, "three"));
+    }
+
+    #[test]
+    fn shrek_turns_players_into_ogre() {
+        let mut server = server();
+        let sanic = join(&mut server, "Sanic", 1);
+        play(&mut server, sanic);
+
+        assert!(chat(&mut server, sanic, "SHREK is love"));
+        assert_eq!(server.players[0].char_id, SHREK_CHARACTER);
+
+        server.players[0].char_id = 0;
+        server.match_settings.allowed_tiers = AllowedTiers::NormalOnly;
+        assert!(chat(&mut server, sanic, "shrek"));
+        assert_eq!(server.players[0].char_id, 0);
+    }
+
+    #[test]
+    fn chat_from_unknown_adress_is_dropped() {
+        let mut server = server();
+        let stranger = SocketAddr::new(IpAddr::from([127, 0, 0, 1]), 9);
+        assert!(!chat(&mut server, stranger, "hello"));
+    }
+
+    #[test]
+    fn sequenced_rotation_wraps_around() {
+        let cases = [
+            //stage count, current stage, next stage
+            (5, 0, 1),
+            (5, 3, 4),
+            (5, 4, 0),
+            (2, 1, 0),
+        ];
+
+        for (stage_count, current, next) in cases {
+            let mut server = server();
+            server.config.stage_count = stage_count;
+            server.match_settings.stage_rotation_mode = StageRotationMode::Sequenced;
+            server.match_settings.stage_id = current;
+
+            assert!(server.rotate_stage(), "{current} of {stage_count}");
+            assert_eq!(server.match_settings.stage_id, next);
+        }
+    }
+
+    #[test]
+    fn random_rotation_never_repeats_the_stage() {
+        let cases = [
+            //stage count, current stage
+            (5, 0),
+            (5, 2),
+            (5, 4),
+            (2, 0),
+            (2, 1),
+        ];
+
+        for (stage_count, current) in cases {
+            let mut server = server();
+            server.config.stage_count = stage_count;
+            server.match_settings.stage_rotation_mode = StageRotationMode::Random;
+            for _ in 0..100 {
+                server.match_settings.stage_id = current;
+                assert!(server.rotate_stage(), "{current} of {stage_count}");
+                let stage = server.match_settings.stage_id;
+                assert_ne!(stage, current);
+                assert!(
+                    (0..stage_count).contains(&stage),
+                    "{stage} of {stage_count}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn stage_stays_without_alternatives() {
+        let cases = [
+            (StageRotationMode::None, 5),
+            (StageRotationMode::Sequenced, 1),
+            (StageRotationMode::Random, 1),
+        ];
+
+        for (mode, stage_count) in cases {
+            let mut server = server();
+            server.config.stage_count = stage_count;
+            server.match_settings.stage_rotation_mode = mode;
+
+            assert!(!server.rotate_stage(), "{mode:?} of {stage_count}");
+            assert_eq!(server.match_settings.stage_id, 0);
+        }
+    }
+
+    #[test]
+    fn tier_cycle_wraps_around() {
+        let cases = [
+            (AllowedTiers::All, AllowedTiers::NormalOnly),
+            (AllowedTiers::NormalOnly, AllowedTiers::OddOnly),
+            (AllowedTiers::OddOnly, AllowedTiers::HyperspeedOnly),
+            (AllowedTiers::HyperspeedOnly, AllowedTiers::NormalOnly),
+            (AllowedTiers::NoHyperspeed, AllowedTiers::NormalOnly),
+        ];
+
+        for (current, next) in cases {
+            let mut server = server();
+            server.match_settings.tier_rotation_mode = TierRotationMode::Cycle;
+            server.match_settings.allowed_tiers = current;
+
+            assert!(server.rotate_tiers(), "{current:?}");
+            assert_eq!(server.match_settings.allowed_tiers, next);
+        }
+    }
+
+    #[test]
+    fn random_tiers_stay_in_rotation() {
+        let rotation = [
+            AllowedTiers::NormalOnly,
+            AllowedTiers::OddOnly,
+            AllowedTiers::HyperspeedOnly,
+        ];
+
+        for mode in [TierRotationMode::Random, TierRotationMode::WeightedRandom] {
+            let mut server = server();
+            server.match_settings.tier_rotation_mode = mode;
+            for _ in 0..100 {
+                let current = server.match_settings.allowed_tiers;
+                let changed = server.rotate_tiers();
+                let tiers = server.match_settings.allowed_tiers;
+                assert_eq!(changed, tiers != current, "{mode:?}");
+                assert!(rotation.contains(&tiers), "{mode:?} picked {tiers:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn tiers_stay_without_rotation() {
+        let mut server = server();
+        server.match_settings.allowed_tiers = AllowedTiers::OddOnly;
+
+        assert!(!server.rotate_tiers());
+        assert_eq!(server.match_settings.allowed_tiers, AllowedTiers::OddOnly);
+    }
 }
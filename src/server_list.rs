@@ -0,0 +1,356 @@
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, Instant},
+};
+
+///How often we remind the server lists that we exist
+pub const PING_INTERVAL: Duration = Duration::from_secs(600);
+
+///How long we wait before retrying after a server list failed, doubled every failure in a row
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+///Give up on a server list that takes longer than this to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+///What one server list said when we added ourselves to it
+pub struct ListResponse {
+    pub url: String,
+    pub result: Result<String, String>,
+}
+
+///How one server list is doing, every list backs off on its own
+struct ListState {
+    failures: u32,
+    next_ping: Instant,
+    ///A request to this list hasn't been answered yet
+    pending: bool,
+}
+
+///Keeps the server on every server list, like AddToServerLists in the C# server
+pub struct ServerList {
+    sender: Sender<ListResponse>,
+    receiver: Receiver<ListResponse>,
+    lists: HashMap<String, ListState>,
+    ///The form we send to every list
+    body: String,
+}
+
+impl Default for ServerList {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        ServerList {
+            sender,
+            receiver,
+            lists: HashMap::new(),
+            body: String::new(),
+        }
+    }
+}
+
+impl ServerList {
+    ///Time until `url` is pinged again, shorter after a failure so we get back on the list quickly
+    pub fn interval(&self, url: &str) -> Duration {
+        backoff(self.lists.get(url).map_or(0, |e| e.failures))
+    }
+
+    ///Make sure every server list can be reached, so a list we can't talk to is caught at startup
+    ///instead of failing forever in the background
+    pub fn check_urls(urls: &[String]) -> Result<(), String> {
+        for url in urls {
+            if url.starts_with("https://") {
+                return Err(format!(
+                    "Server list {url} uses https, which is not supported. Use its http:// adress or set public to false"
+                ));
+            }
+            if !url.starts_with("http://") {
+                return Err(format!("Server list {url} is not an http:// adress"));
+            }
+        }
+        Ok(())
+    }
+
+    ///Add the server to every list right away, in the background.
+    ///Answers are picked up with `responses`, `ping` keeps us on the lists after that.
+    pub fn add(&mut self, urls: &[String], ip: &str, port: i32) {
+        self.body = format!("ip={}&port={}", url_encode(ip), port);
+        for url in urls {
+            let list = self.lists.entry(url.clone()).or_insert(ListState {
+                failures: 0,
+                next_ping: Instant::now(),
+                pending: false,
+            });
+            list.next_ping = Instant::now();
+        }
+        self.ping();
+    }
+
+    ///Add the server again to every list whose interval is over
+    pub fn ping(&mut self) {
+        let now = Instant::now();
+        for (url, list) in self.lists.iter_mut() {
+            if list.pending || list.next_ping > now {
+                continue;
+            }
+            list.pending = true;
+
+            let sender = self.sender.clone();
+            let url = url.clone();
+            let body = self.body.clone();
+            thread::spawn(move || {
+                let result = post(&format!("{}/add/", url.trim_end_matches('/')), &body);
+                let _ = sender.send(ListResponse { url, result });
+            });
+        }
+    }
+
+    ///Everything the server lists answered since the last call
+    pub fn responses(&mut self) -> Vec<ListResponse> {
+        let responses: Vec<ListResponse> = self.receiver.try_iter().collect();
+
+        for response in responses.iter() {
+            let Some(list) = self.lists.get_mut(&response.url) else {
+                continue;
+            };
+            list.pending = false;
+            match response.result {
+                Ok(_) => list.failures = 0,
+                Err(_) => list.failures += 1,
+            }
+            list.next_ping = Instant::now() + backoff(list.failures);
+        }
+
+        responses
+    }
+}
+
+///Time until the next ping after `failures` failures in a row
+fn backoff(failures: u32) -> Duration {
+    if failures == 0 {
+        return PING_INTERVAL;
+    }
+
+    let backoff = RETRY_INTERVAL * 2u32.saturating_pow(failures - 1);
+    backoff.min(PING_INTERVAL)
+}
+
+///Send a form as a POST request and return the body of the answer
+fn post(url: &str, body: &str) -> Result<String, String> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        return Err(format!(
+            "Only http:// server lists are supported, not {url}"
+        ));
+    };
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let addr = match host.contains(':') {
+        true => host.to_owned(),
+        false => format!("{host}:80"),
+    };
+
+    let socket = addr
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or(format!("Could not find {host}"))?;
+
+    let mut stream = TcpStream::connect_timeout(&socket, TIMEOUT).map_err(|e| e.to_string())?;
+    stream
+        .set_read_timeout(Some(TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    stream
+        .set_write_timeout(Some(TIMEOUT))
+        .map_err(|e| e.to_string())?;
+
+    let request = format!(
+        "POST {path} HTTP/1.1\r\n\
+        Host: {host}\r\n\
+        Content-Type: application/x-www-form-urlencoded\r\n\
+        Content-Length: {}\r\n\
+        Connection: close\r\n\
+        \r\n\
+        {body}",
+        body.len()
+    );
+    stream
+        .write_all(request.as_bytes())
+        .map_err(|e| e.to_string())?;
+
+    let mut response = String::new();
+    stream
+        .read_to_string(&mut response)
+        .map_err(|e| e.to_string())?;
+
+    let (head, content) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    let status = head.lines().next().unwrap_or_default();
+    let code = status.split_whitespace().nth(1).unwrap_or_default();
+
+    match code.starts_with('2') {
+        true => Ok(content.to_owned()),
+        false => Err(format!("{status} {content}")),
+    }
+}
+
+///Escape everything that isn't allowed as is in a form value
+fn url_encode(text: &str) -> String {
+    text.bytes()
+        .map(|e| match e {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (e as char).to_string()
+            }
+            _ => format!("%{e:02X}"),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::TcpListener,
+        time::{Duration, Instant},
+    };
+
+    ///A server list that answers a request with every status in turn, the requests it got are sent back
+    fn stub(statuses: &[&'static str]) -> (String, Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+        let statuses = statuses.to_vec();
+
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = vec![];
+                let mut chunk = [0; 1024];
+
+                //Read until the whole body the Content-Length promises is there
+                loop {
+                    let read = stream.read(&mut chunk).unwrap();
+                    request.extend_from_slice(&chunk[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length: usize = head
+                            .lines()
+                            .find_map(|e| e.strip_prefix("Content-Length: "))
+                            .and_then(|e| e.trim().parse().ok())
+                            .unwrap_or(0);
+                        if body.len() >= length {
+                            sender.send(text).unwrap();
+                            break;
+                        }
+                    }
+                    if read == 0 {
+                        break;
+                    }
+                }
+
+                let answer = format!("HTTP/1.1 {status}\r\nConnection: close\r\n\r\nanswer");
+                stream.write_all(answer.as_bytes()).unwrap();
+            }
+        });
+
+        (url, requests)
+    }
+
+    ///Add ourselves to the lists and wait until every one of them answered
+    fn register(list: &mut ServerList, urls: &[String]) -> Vec<ListResponse> {
+        list.add(urls, "1.2.3.4", 7878);
+
+        let mut responses = vec![];
+        let start = Instant::now();
+        while responses.len() < urls.len() {
+            responses.extend(list.responses());
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "no answer from the stub"
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+        responses
+    }
+
+    #[test]
+    fn posts_the_adress_to_the_add_page() {
+        let mut list = ServerList::default();
+        let (url, requests) = stub(&["200 OK"]);
+        let response = register(&mut list, &[url]).remove(0);
+        let request = requests.recv().unwrap();
+
+        assert!(request.starts_with("POST /add/ HTTP/1.1\r\n"));
+        assert!(request.ends_with("\r\n\r\nip=1.2.3.4&port=7878"));
+        assert_eq!(response.result, Ok("answer".to_owned()));
+    }
+
+    #[test]
+    fn error_status_is_a_failure() {
+        let mut list = ServerList::default();
+        let (url, _requests) = stub(&["500 Internal Server Error"]);
+        let response = register(&mut list, &[url]).remove(0);
+
+        assert_eq!(
+            response.result,
+            Err("HTTP/1.1 500 Internal Server Error answer".to_owned())
+        );
+    }
+
+    #[test]
+    fn backs_off_after_failures_and_resets_after_success() {
+        let mut list = ServerList::default();
+        let failing = "500 Internal Server Error";
+        let (broken, _broken_requests) = stub(&[failing, failing, "200 OK"]);
+        let (healthy, _healthy_requests) = stub(&["200 OK", "200 OK", "200 OK"]);
+        let urls = [broken.clone(), healthy.clone()];
+        assert_eq!(list.interval(&broken), PING_INTERVAL);
+
+        register(&mut list, &urls);
+        assert_eq!(list.interval(&broken), RETRY_INTERVAL);
+        assert_eq!(list.interval(&healthy), PING_INTERVAL);
+
+        //The healthy list answering doesn't reset the broken one
+        register(&mut list, &urls);
+        assert_eq!(list.interval(&broken), RETRY_INTERVAL * 2);
+        assert_eq!(list.interval(&healthy), PING_INTERVAL);
+
+        register(&mut list, &urls);
+        assert_eq!(list.interval(&broken), PING_INTERVAL);
+    }
+
+    #[test]
+    fn only_lists_that_are_due_are_pinged() {
+        let mut list = ServerList::default();
+        let (broken, _broken_requests) = stub(&["500 Internal Server Error", "200 OK"]);
+        let (healthy, healthy_requests) = stub(&["200 OK", "200 OK"]);
+        register(&mut list, &[broken.clone(), healthy]);
+        healthy_requests.recv().unwrap();
+
+        //Pretend the retry interval of the broken list is over
+        list.lists.get_mut(&broken).unwrap().next_ping = Instant::now();
+        list.ping();
+
+        let start = Instant::now();
+        let responses = loop {
+            let responses = list.responses();
+            if !responses.is_empty() {
+                break responses;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].url, broken);
+        assert!(healthy_requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn https_lists_are_rejected() {
+        let urls = ["https://sanicball.bdgr.zone/servers".to_owned()];
+        assert!(ServerList::check_urls(&urls).is_err());
+        assert!(ServerList::check_urls(&["http://localhost/servers".to_owned()]).is_ok());
+    }
+}
@@ -1,19 +1,62 @@
 use std::{
     fmt::{Display, Formatter},
     net::{IpAddr, Ipv4Addr, SocketAddr},
-    str, usize,
+    str,
 };
 
 use crate::{
-    data::{Client, Player, PlayerPosition, Settings, Stopwatch},
+    buffer::HEADER_SIZE,
+    data::{Client, Player, PlayerPosition, Settings, Timer},
     game::{CtrlType, GameHeader},
     headers::{Header, Result},
 };
 
+///A C# Guid is always this many bytes
+pub const GUID_SIZE: usize = 16;
+
+///Why a message couldn't be read, the packet it came in gets dropped
+#[derive(Clone, Debug)]
+pub enum DecodeError {
+    ///The message ended before everything was read
+    Truncated,
+    ///A string length had too many bytes to be a valid varint
+    BadVarint,
+    InvalidUtf8,
+    UnknownGameHeader(u8),
+    UnknownCtrlType(u8),
+    ///A guid that claims to be bigger (or smaller) than a C# Guid
+    OversizeGuid(i32),
+    ///A guid string inside a match message that isn't formatted like a C# Guid
+    BadGuid(String),
+    ///A match message the server couldn't make sense of
+    BadJson(String),
+    ///A fragment that doesn't fit in the message it claims to be part of
+    BadFragment,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "Message is shorter than expected"),
+            DecodeError::BadVarint => write!(f, "String length is not a valid varint"),
+            DecodeError::InvalidUtf8 => write!(f, "String is not valid UTF-8"),
+            DecodeError::UnknownGameHeader(byte) => write!(f, "Unknown game header {byte}"),
+            DecodeError::UnknownCtrlType(byte) => write!(f, "Unknown control type {byte}"),
+            DecodeError::OversizeGuid(size) => write!(f, "Guid has a size of {size} bytes"),
+            DecodeError::BadGuid(guid) => write!(f, "Invalid guid {guid:?}"),
+            DecodeError::BadJson(error) => write!(f, "Invalid match message ({error})"),
+            DecodeError::BadFragment => write!(f, "Fragment does not fit its message"),
+        }
+    }
+}
+
+pub type ReadResult<T> = std::result::Result<T, DecodeError>;
+
 #[derive(Clone)]
 pub struct Stream {
     pub header: Result,
     pub header_byte: u8,
+    pub fragment: bool,
     pub sequence: u16,
     pub size: u16,
     pub origin: SocketAddr,
@@ -22,7 +65,12 @@ pub struct Stream {
 }
 
 impl Stream {
-    pub fn new(stream: &[u8; 1500], size: usize, socket: SocketAddr) -> Self {
+    ///Read the message at the start of `stream`, there can be more messages after it
+    pub fn new(stream: &[u8], socket: SocketAddr) -> ReadResult<Self> {
+        if stream.len() < HEADER_SIZE {
+            return Err(DecodeError::Truncated);
+        }
+
         let sequence1: u16 = stream[1].into();
         let sequence1: u16 = sequence1 >> 1;
         let sequence2: u16 = stream[2].into();
@@ -33,17 +81,40 @@ impl Stream {
         let size2: u16 = stream[4].into();
         let size2: u16 = size2 << 8;
 
-        //assert_eq!(usize::from((size1 | size2) / 8), size - 5);
+        //Rounded up to whole bytes, the rest of the last byte is padding
+        let size = HEADER_SIZE + usize::from(size1 | size2).div_ceil(8);
+        if stream.len() < size {
+            return Err(DecodeError::Truncated);
+        }
 
-        Stream {
+        Ok(Stream {
             header: stream[0].try_into(),
             header_byte: stream[0],
+            fragment: stream[1] & 1 == 1,
             sequence: sequence1 | sequence2,
             size: size1 | size2,
             origin: socket,
-            data: (stream[5..size]).to_vec(),
+            data: (stream[HEADER_SIZE..size]).to_vec(),
             ptr: 0,
+        })
+    }
+
+    ///Split a datagram into the messages Lidgren packed inside it.
+    ///A broken message ends the list, there's no telling where the next one would start.
+    pub fn parse(datagram: &[u8], socket: SocketAddr) -> Vec<ReadResult<Self>> {
+        let mut streams = vec![];
+        let mut ptr = 0;
+
+        while ptr < datagram.len() {
+            let stream = Stream::new(&datagram[ptr..], socket);
+            match &stream {
+                Ok(message) => ptr += HEADER_SIZE + message.data.len(),
+                Err(_) => ptr = datagram.len(),
+            }
+            streams.push(stream);
         }
+
+        streams
     }
 
     pub fn from(stream: &Stream) -> Self {
@@ -52,90 +123,134 @@ impl Stream {
         clone
     }
 
-    pub fn read_byte(&mut self) -> u8 {
-        self.ptr += 1;
-        self.data[self.ptr - 1]
+    ///The whole message a fragment was part of, once every fragment arrived
+    pub fn assembled(&self, data: Vec<u8>) -> Self {
+        let mut whole = Stream::from(self);
+        whole.fragment = false;
+        whole.size = u16::try_from(data.len() * 8).unwrap_or(u16::MAX);
+        whole.data = data;
+        whole
+    }
+
+    ///Everything that wasn't read yet
+    pub fn rest(&self) -> &[u8] {
+        &self.data[self.ptr.min(self.data.len())..]
     }
 
-    pub fn read_bytes(&mut self) -> &[u8] {
-        self.ptr += 1;
-        let size: usize = self.data[self.ptr - 1].into();
+    ///How many bytes are left to read
+    pub fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.ptr)
+    }
+
+    ///Move the pointer over the next `size` bytes and return them, if there are that many left
+    fn take(&mut self, size: usize) -> ReadResult<&[u8]> {
+        if size > self.remaining() {
+            return Err(DecodeError::Truncated);
+        }
+
         self.ptr += size;
-        &self.data[(self.ptr - size)..self.ptr]
+        Ok(&self.data[(self.ptr - size)..self.ptr])
     }
 
-    pub fn read_string(&mut self) -> String {
-        let mut size: usize = 0;
+    pub fn read_byte(&mut self) -> ReadResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bytes(&mut self) -> ReadResult<&[u8]> {
+        let size: usize = self.read_byte()?.into();
+        self.take(size)
+    }
+
+    ///Read a number stored 7 bits at a time, the high bit says if there's more to come
+    pub fn read_varint(&mut self) -> ReadResult<usize> {
+        let mut num: usize = 0;
         let mut shift = 0;
-        let mut byte: usize = self.read_byte().into();
+        let mut byte: usize = self.read_byte()?.into();
         while byte & 0x80 == 0x80 {
-            size |= (byte & 0x7F) << shift;
-            byte = self.read_byte().into();
+            num |= (byte & 0x7F) << shift;
+            byte = self.read_byte()?.into();
             shift += 7;
+
+            //A 32 bit number never needs more than 5 bytes
+            if shift > 28 {
+                return Err(DecodeError::BadVarint);
+            }
         }
-        size |= (byte & 0x7F) << shift;
+        num |= (byte & 0x7F) << shift;
 
-        self.ptr += size;
-        let byte_string = &self.data[(self.ptr - size)..self.ptr];
+        Ok(num)
+    }
+
+    pub fn read_string(&mut self) -> ReadResult<String> {
+        let size = self.read_varint()?;
+        let byte_string = self.take(size)?;
 
-        str::from_utf8(byte_string).unwrap().to_owned()
+        match str::from_utf8(byte_string) {
+            Ok(string) => Ok(string.to_owned()),
+            Err(_) => Err(DecodeError::InvalidUtf8),
+        }
     }
 
-    pub fn read_i32(&mut self) -> i32 {
-        self.ptr += 4;
-        let bytes: [u8; 4] = self.data[self.ptr - 4..self.ptr].try_into().unwrap();
-        i32::from_le_bytes(bytes)
+    pub fn read_i32(&mut self) -> ReadResult<i32> {
+        let bytes: [u8; 4] = self.take(4)?.try_into().unwrap();
+        Ok(i32::from_le_bytes(bytes))
     }
 
-    pub fn read_f32(&mut self) -> f32 {
-        self.ptr += 4;
-        let bytes: [u8; 4] = self.data[self.ptr - 4..self.ptr].try_into().unwrap();
-        f32::from_le_bytes(bytes)
+    pub fn read_i64(&mut self) -> ReadResult<i64> {
+        let bytes: [u8; 8] = self.take(8)?.try_into().unwrap();
+        Ok(i64::from_le_bytes(bytes))
     }
 
-    pub fn read_bool(&mut self) -> bool {
-        self.read_byte() == 0
+    pub fn read_f32(&mut self) -> ReadResult<f32> {
+        let bytes: [u8; 4] = self.take(4)?.try_into().unwrap();
+        Ok(f32::from_le_bytes(bytes))
     }
 
-    pub fn read_guid(&mut self) -> Vec<u8> {
-        let size: usize = self.read_i32().try_into().unwrap();
+    pub fn read_bool(&mut self) -> ReadResult<bool> {
+        Ok(self.read_byte()? != 0)
+    }
 
-        self.ptr += size;
-        self.data[(self.ptr - size)..self.ptr].to_vec()
+    pub fn read_guid(&mut self) -> ReadResult<Vec<u8>> {
+        let size = self.read_i32()?;
+        if usize::try_from(size) != Ok(GUID_SIZE) {
+            return Err(DecodeError::OversizeGuid(size));
+        }
+
+        Ok(self.take(GUID_SIZE)?.to_vec())
     }
 
-    pub fn read_game_header(&mut self) -> GameHeader {
-        let byte = self.read_byte();
+    pub fn read_game_header(&mut self) -> ReadResult<GameHeader> {
+        let byte = self.read_byte()?;
         match byte {
-            0 => GameHeader::MatchMessage,
-            1 => GameHeader::InitMessage,
-            2 => GameHeader::PlayerMovementMessage,
-            _ => panic!("You done goofed {byte}"),
+            0 => Ok(GameHeader::MatchMessage),
+            1 => Ok(GameHeader::InitMessage),
+            2 => Ok(GameHeader::PlayerMovementMessage),
+            _ => Err(DecodeError::UnknownGameHeader(byte)),
         }
     }
 
-    pub fn read_vec3(&mut self) -> [f32; 3] {
-        [self.read_f32(), self.read_f32(), self.read_f32()]
+    pub fn read_vec3(&mut self) -> ReadResult<[f32; 3]> {
+        Ok([self.read_f32()?, self.read_f32()?, self.read_f32()?])
     }
 
-    pub fn read_vec4(&mut self) -> [f32; 4] {
-        [
-            self.read_f32(),
-            self.read_f32(),
-            self.read_f32(),
-            self.read_f32(),
-        ]
+    pub fn read_vec4(&mut self) -> ReadResult<[f32; 4]> {
+        Ok([
+            self.read_f32()?,
+            self.read_f32()?,
+            self.read_f32()?,
+            self.read_f32()?,
+        ])
     }
 
-    pub fn read_clients(&mut self) -> Vec<Client> {
+    pub fn read_clients(&mut self) -> ReadResult<Vec<Client>> {
         let mut clients = vec![];
 
-        let mut size = self.read_i32();
+        let mut size = self.read_i32()?;
         while size > 0 {
             size -= 1;
 
-            let guid = self.read_guid();
-            let name = self.read_string();
+            let guid = self.read_guid()?;
+            let name = self.read_string()?;
 
             clients.push(Client {
                 guid,
@@ -143,69 +258,71 @@ impl Stream {
                 connection: self.origin,
                 is_loading: false,
                 wants_lobby: false,
-                counter: 0,
+                is_admin: false,
+                chat_times: vec![],
             })
         }
 
-        clients
+        Ok(clients)
     }
 
-    pub fn read_players(&mut self) -> Vec<Player> {
+    pub fn read_players(&mut self) -> ReadResult<Vec<Player>> {
         let mut players = vec![];
 
-        let mut size = self.read_i32();
+        let mut size = self.read_i32()?;
         while size > 0 {
             size -= 1;
             players.push(Player {
-                guid: self.read_guid(),
-                ctrl_type: self.read_i32(),
-                ready_to_race: self.read_bool(),
-                char_id: self.read_i32(),
+                guid: self.read_guid()?,
+                ctrl_type: self.read_i32()?,
+                ready_to_race: self.read_bool()?,
+                char_id: self.read_i32()?,
                 is_racing: false,
-                race_timeout: Stopwatch {},
+                race_timeout: Timer::default(),
+                timeout_warned: false,
                 has_timed_out: false,
             });
         }
 
-        players
+        Ok(players)
     }
 
-    pub fn read_ctrl_type(&mut self) -> CtrlType {
-        match self.read_byte() {
-            0 => CtrlType::Keyboard,
-            1 => CtrlType::Joystick1,
-            2 => CtrlType::Joystick2,
-            3 => CtrlType::Joystick3,
-            4 => CtrlType::Joystick4,
-            _ => panic!(),
+    pub fn read_ctrl_type(&mut self) -> ReadResult<CtrlType> {
+        match self.read_byte()? {
+            0 => Ok(CtrlType::Keyboard),
+            1 => Ok(CtrlType::Joystick1),
+            2 => Ok(CtrlType::Joystick2),
+            3 => Ok(CtrlType::Joystick3),
+            4 => Ok(CtrlType::Joystick4),
+            byte => Err(DecodeError::UnknownCtrlType(byte)),
         }
     }
 
-    pub fn read_player_pos(&mut self) -> PlayerPosition {
-        PlayerPosition {
-            guid: self.read_guid(),
-            ctrl_type: self.read_ctrl_type(),
-            position: self.read_vec3(),
-            rotation: self.read_vec4(),
-            velocity: self.read_vec3(),
-            angular_velocity: self.read_vec3(),
-            direction: self.read_vec3(),
-        }
+    pub fn read_player_pos(&mut self) -> ReadResult<PlayerPosition> {
+        Ok(PlayerPosition {
+            guid: self.read_guid()?,
+            ctrl_type: self.read_ctrl_type()?,
+            position: self.read_vec3()?,
+            rotation: self.read_vec4()?,
+            velocity: self.read_vec3()?,
+            angular_velocity: self.read_vec3()?,
+            direction: self.read_vec3()?,
+        })
     }
 
-    pub fn read_settings(&mut self) -> Settings {
+    pub fn read_settings(&mut self) -> ReadResult<Settings> {
         //Match settings properties, written in the order they appear in code
-        Settings {
-            stage_id: self.read_i32(),
-            laps: self.read_i32(),
-            ai_count: self.read_i32(),
-            ai_skill: self.read_i32(),
-            auto_start_time: self.read_i32(),
-            auto_start_min_players: self.read_i32(),
-            auto_return_time: self.read_i32(),
-            vote_ratio: self.read_f32(),
-            stage_rotation_mode: self.read_i32(),
-        }
+        Ok(Settings {
+            stage_id: self.read_i32()?,
+            laps: self.read_i32()?,
+            ai_count: self.read_i32()?,
+            ai_skill: self.read_i32()?,
+            auto_start_time: self.read_i32()?,
+            auto_start_min_players: self.read_i32()?,
+            auto_return_time: self.read_i32()?,
+            vote_ratio: self.read_f32()?,
+            stage_rotation_mode: self.read_i32()?,
+        })
     }
 
     pub fn dump(&self) {
@@ -218,26 +335,26 @@ impl Display for Stream {
         let clone = Stream::from(self);
         match clone.header_byte {
             0 => write!(f, "Should not happen (unconnected)"),
-            1 => write!(f, "User Unreliable (disabled)"),
-            2..=33 => write!(f, "User Sequenced (disabled)"),
-            34 => write!(f, "User Reliable Unordered (disabled)"),
-            35..=66 => write!(f, "User Reliable Sequenced (disabled)"),
-            67..=98 => write!(f, "User Reliable Ordered {}", clone.header_byte),
+            1 => write!(f, "User Unreliable"),
+            2..=33 => write!(f, "User Sequenced {}", clone.header_byte - 1),
+            34 => write!(f, "User Reliable Unordered"),
+            35..=66 => write!(f, "User Reliable Sequenced {}", clone.header_byte - 34),
+            67..=98 => write!(f, "User Reliable Ordered {}", clone.header_byte - 66),
             99..=127 => write!(f, "Unused 1-28"),
             128 => write!(f, "Library error (disabled)"),
             129 => write!(f, "Ping"),
-            130 => write!(f, "Pong (disabled)"),
+            130 => write!(f, "Pong"),
             131 => write!(f, "Connect"),
             132 => write!(f, "Connect Response (disabled)"),
             133 => write!(f, "Connection Established"),
             134 => write!(f, "Ackwoledge"),
-            135 => write!(f, "Disconnect (disabled)"),
-            136 => write!(f, "Discovery (disabled)"),
+            135 => write!(f, "Disconnect"),
+            136 => write!(f, "Discovery"),
             137 => write!(f, "Discovery Response (disabled)"),
             138 => write!(f, "Nat Punch Message (disabled)"),
             139 => write!(f, "Nat Intoduction (disabled)"),
-            140 => write!(f, "Expand MTU Request (disabled)"),
-            141 => write!(f, "Expand MTU Succsess (disabled)"),
+            140 => write!(f, "Expand MTU Request"),
+            141 => write!(f, "Expand MTU Succsess"),
             142 => write!(f, "Nat Introduction Confirm Request (disabled)"),
             143 => write!(f, "Nat Introduction Confirmed (disabled)"),
             144..=255 => write!(f, "Out of Bounds"),
@@ -250,6 +367,7 @@ impl Default for Stream {
         Self {
             header: Ok(Header::Unconnected),
             header_byte: 0,
+            fragment: false,
             sequence: 0,
             size: 0,
             origin: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
@@ -258,3 +376,75 @@ impl Default for Stream {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::to_byte;
+
+    fn origin() -> SocketAddr {
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 25000)
+    }
+
+    ///A message the way Lidgren puts it on the wire
+    fn message(header: u8, sequence: u16, data: &[u8]) -> Vec<u8> {
+        let bits = u16::try_from(data.len() * 8).unwrap();
+        let mut message = vec![
+            header,
+            to_byte(usize::from(sequence << 1)),
+            to_byte(usize::from(sequence >> 7)),
+            to_byte(usize::from(bits)),
+            to_byte(usize::from(bits >> 8)),
+        ];
+        message.extend_from_slice(data);
+        message
+    }
+
+    #[test]
+    fn parse_splits_every_message() {
+        let mut datagram = message(1, 0, &[1, 2, 3]);
+        datagram.extend(message(67, 300, &[]));
+        datagram.extend(message(129, 5, &[42]));
+
+        let streams: Vec<Stream> = Stream::parse(&datagram, origin())
+            .into_iter()
+            .map(|e| e.unwrap())
+            .collect();
+
+        assert_eq!(streams.len(), 3);
+        assert_eq!(streams[0].header_byte, 1);
+        assert_eq!(streams[0].data, [1, 2, 3]);
+        assert_eq!(streams[1].header_byte, 67);
+        assert_eq!(streams[1].sequence, 300);
+        assert!(streams[1].data.is_empty());
+        assert_eq!(streams[2].header_byte, 129);
+        assert_eq!(streams[2].sequence, 5);
+        assert_eq!(streams[2].data, [42]);
+    }
+
+    #[test]
+    fn parse_stops_at_a_truncated_message() {
+        let mut datagram = message(1, 0, &[1, 2, 3]);
+        let mut cut = message(1, 1, &[4, 5, 6, 7]);
+        cut.truncate(cut.len() - 2);
+        datagram.extend(cut);
+
+        let streams = Stream::parse(&datagram, origin());
+
+        assert_eq!(streams.len(), 2);
+        assert_eq!(streams[0].as_ref().unwrap().data, [1, 2, 3]);
+        assert!(matches!(streams[1], Err(DecodeError::Truncated)));
+    }
+
+    #[test]
+    fn parse_stops_at_a_truncated_header() {
+        let mut datagram = message(1, 0, &[1]);
+        datagram.extend([1, 0]);
+
+        let streams = Stream::parse(&datagram, origin());
+
+        assert_eq!(streams.len(), 2);
+        assert!(streams[0].is_ok());
+        assert!(matches!(streams[1], Err(DecodeError::Truncated)));
+    }
+}
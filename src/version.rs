@@ -1,12 +1,12 @@
 //Used for identifing
-const APP_ID: &str = "Sanicball";
+pub const APP_ID: &str = "Sanicball";
 
 // For in-game tags and for elsewhere
-const VERSION: &str = "Version 24: NEO";
-const VERSION_FLOAT: f32 = 0.82;
+pub const VERSION: &str = "Version 24: NEO";
+pub const VERSION_FLOAT: f32 = 0.82;
 
 //Funny haha meme bro
-const TAGLINE: &str = "Reborn in Neon";
+pub const TAGLINE: &str = "Reborn in Neon";
 
 //I shouldn't need to write what this does
-const IS_TESTING: bool = false;
+pub const IS_TESTING: bool = false;